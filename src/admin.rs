@@ -0,0 +1,391 @@
+use actix_web::{web, HttpRequest, HttpResponse, FromRequest, Responder};
+use actix_web::dev::{Payload, PayloadStream};
+use futures::future;
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::MutexGuard;
+use crate::{MainState, SiteState};
+use crate::api::site_state;
+use crate::client_addr::ClientAddr;
+use crate::graph::UpdatePolicy;
+use crate::journal::Decision;
+use crate::node_id::NodeID;
+use crate::persistence::{Override, Pause, PersistentState, RolloutState};
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg
+        .service(
+            web::resource("/sites/{site}/{branch}/pause")
+                .route(web::post().to(pause))
+        )
+        .service(
+            web::resource("/sites/{site}/{branch}/resume")
+                .route(web::post().to(resume))
+        )
+        .service(
+            web::resource("/sites/{site}/{branch}/refresh")
+                .route(web::post().to(refresh))
+        )
+        .service(
+            web::resource("/sites/{site}/{branch}/nodes/{node_id}/override")
+                .route(web::post().to(set_override))
+                .route(web::delete().to(clear_override))
+        )
+        .service(
+            web::resource("/sites/{site}/{branch}/nodes/{node_id}/reset-attempts")
+                .route(web::post().to(reset_attempts))
+        )
+        .service(
+            web::resource("/sites/{site}/{branch}/nodes/{node_id}/mark-broken")
+                .route(web::post().to(mark_broken))
+        )
+        .service(
+            web::resource("/sites/{site}/{branch}/nodes/{node_id}/mark-finished")
+                .route(web::post().to(mark_finished))
+        )
+        .service(
+            web::resource("/reload")
+                .route(web::post().to(reload))
+        );
+}
+
+/// An administrator authenticated by one of the configured bearer tokens
+pub struct Admin {
+    pub name: String,
+    pub ip: Option<IpAddr>
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl FromRequest for Admin {
+    type Error = actix_web::Error;
+    type Future = future::Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload<PayloadStream>) -> Self::Future {
+        let unauthorized = || -> actix_web::Error {
+            actix_web::error::InternalError::from_response(
+                "Unauthorized",
+                HttpResponse::Unauthorized()
+                    .header("WWW-Authenticate", "Bearer")
+                    .body("401 Unauthorized")
+            ).into()
+        };
+        future::ready((|| {
+            let state = req.app_data::<web::Data<Arc<MainState>>>()
+                .ok_or_else(|| actix_web::error::ErrorInternalServerError("Missing state"))?;
+            let token = req.headers().get("Authorization")
+                .and_then(|hdr| hdr.to_str().ok())
+                .and_then(|hdr| hdr.strip_prefix("Bearer "))
+                .ok_or_else(unauthorized)?;
+            let admin_tokens = state.admin_tokens.read().unwrap();
+            let admin = admin_tokens.iter()
+                .find(|t| constant_time_eq(t.token.as_bytes(), token.trim().as_bytes()))
+                .ok_or_else(unauthorized)?;
+            Ok(Admin {
+                name: admin.name.clone(),
+                ip: ClientAddr::of(req).ok().map(|addr| addr.0)
+            })
+        })())
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct ActionBody {
+    reason: Option<String>
+}
+
+#[derive(Deserialize)]
+struct OverrideBody {
+    policy: UpdatePolicy,
+    reason: Option<String>
+}
+
+fn parse_node(node_id: &str) -> Result<NodeID, actix_web::Error> {
+    node_id.parse::<NodeID>()
+        .map_err(|_| actix_web::error::ErrorBadRequest("Invalid node id"))
+}
+
+fn with_reason(action: String, reason: &Option<String>) -> String {
+    match reason {
+        Some(reason) => format!("{}: {}", action, reason),
+        None => action
+    }
+}
+
+/// Logs the action and records it in the journal
+async fn record(site: &SiteState, admin: &Admin, node: Option<NodeID>, action: String) {
+    let hostname = match node {
+        Some(node) => {
+            let graph = site.graph.read().await;
+            graph.node_ids.get(&node).map(|key| graph.nodes[*key].node.hostname.clone())
+        },
+        None => None
+    };
+    let config = site.config();
+    log::info!(
+        "Admin action on site {}/{} by {}: {}",
+        config.name,
+        config.branch,
+        admin.name,
+        action
+    );
+    site.journal().record_node(Decision::Admin, node, hostname, admin.ip, format!("{} (by {})", action, admin.name));
+}
+
+async fn pause(
+    state: web::Data<Arc<MainState>>,
+    web::Path((site, branch)): web::Path<(String, String)>,
+    admin: Admin,
+    body: Option<web::Json<ActionBody>>
+) -> impl Responder {
+    let site_state = site_state(&state, site, branch)?;
+    let body = body.map(web::Json::into_inner).unwrap_or_default();
+    let action = with_reason("rollout paused".to_owned(), &body.reason);
+    {
+        let mut persistent = site_state.persistent.lock().await;
+        let rollout = persistent.rollout_state_mut(site_state.config().dry_run).1;
+        if rollout.paused.is_some() {
+            return Err(actix_web::error::ErrorConflict("Rollout is already paused"));
+        }
+        rollout.paused = Some(Pause {
+            since: chrono::Utc::now(),
+            reason: format!("{} (by {})", action, admin.name)
+        });
+    }
+    site_state.graph.write().await.paused = true;
+    site_state.request_save();
+    record(&site_state, &admin, None, action).await;
+    Ok::<_, actix_web::Error>(HttpResponse::NoContent().finish())
+}
+
+async fn resume(
+    state: web::Data<Arc<MainState>>,
+    web::Path((site, branch)): web::Path<(String, String)>,
+    admin: Admin,
+    body: Option<web::Json<ActionBody>>
+) -> impl Responder {
+    let site_state = site_state(&state, site, branch)?;
+    let body = body.map(web::Json::into_inner).unwrap_or_default();
+    {
+        let mut persistent = site_state.persistent.lock().await;
+        let rollout = persistent.rollout_state_mut(site_state.config().dry_run).1;
+        if rollout.paused.take().is_none() {
+            return Err(actix_web::error::ErrorConflict("Rollout is not paused"));
+        }
+        // Start with a fresh window, otherwise the old failures would pause it right away
+        rollout.outcomes.clear();
+    }
+    site_state.graph.write().await.paused = false;
+    site_state.request_save();
+    record(&site_state, &admin, None, with_reason("rollout resumed".to_owned(), &body.reason)).await;
+    Ok::<_, actix_web::Error>(HttpResponse::NoContent().finish())
+}
+
+async fn refresh(
+    state: web::Data<Arc<MainState>>,
+    web::Path((site, branch)): web::Path<(String, String)>,
+    admin: Admin
+) -> impl Responder {
+    let site_state = site_state(&state, site, branch)?;
+    site_state.refresh.notify();
+    record(&site_state, &admin, None, "refresh requested".to_owned()).await;
+    Ok::<_, actix_web::Error>(HttpResponse::Accepted().finish())
+}
+
+/// Locks the state of the site if the node is in the mesh data or in the state. Others are refused,
+/// as whatever is stored about a mistyped node id would be kept forever
+async fn lock_known_node(site: &SiteState, node: NodeID) -> Result<MutexGuard<'_, PersistentState>, actix_web::Error> {
+    let in_graph = site.graph.read().await.node_ids.contains_key(&node);
+    let persistent = site.persistent.lock().await;
+    if !in_graph && !persistent.last_seen.contains_key(&node) {
+        return Err(actix_web::error::ErrorNotFound("Unknown node"));
+    }
+    Ok(persistent)
+}
+
+/// Stores the override. It takes effect with the refresh triggered right away
+async fn store_override(
+    site: &SiteState,
+    admin: &Admin,
+    node: NodeID,
+    policy: UpdatePolicy,
+    reason: Option<String>
+) -> Result<(), actix_web::Error> {
+    let action = with_reason(format!("policy overridden to {}", policy.name()), &reason);
+    {
+        let mut persistent = lock_known_node(site, node).await?;
+        persistent.overrides.insert(node, Override {
+            policy,
+            by: admin.name.clone(),
+            at: chrono::Utc::now(),
+            reason
+        });
+        persistent.mark_dirty(node);
+    }
+    site.request_save();
+    site.refresh.notify();
+    record(site, admin, Some(node), action).await;
+    Ok(())
+}
+
+async fn set_override(
+    state: web::Data<Arc<MainState>>,
+    web::Path((site, branch, node_id)): web::Path<(String, String, String)>,
+    admin: Admin,
+    body: web::Json<OverrideBody>
+) -> impl Responder {
+    let site_state = site_state(&state, site, branch)?;
+    let node = parse_node(&node_id)?;
+    let body = body.into_inner();
+    store_override(&site_state, &admin, node, body.policy, body.reason).await?;
+    Ok::<_, actix_web::Error>(HttpResponse::NoContent().finish())
+}
+
+async fn clear_override(
+    state: web::Data<Arc<MainState>>,
+    web::Path((site, branch, node_id)): web::Path<(String, String, String)>,
+    admin: Admin
+) -> impl Responder {
+    let site_state = site_state(&state, site, branch)?;
+    let node = parse_node(&node_id)?;
+    {
+        let mut persistent = site_state.persistent.lock().await;
+        if persistent.overrides.remove(&node).is_none() {
+            return Err(actix_web::error::ErrorNotFound("Node has no override"));
+        }
+        persistent.mark_dirty(node);
+    }
+    site_state.request_save();
+    site_state.refresh.notify();
+    record(&site_state, &admin, Some(node), "override cleared".to_owned()).await;
+    Ok::<_, actix_web::Error>(HttpResponse::NoContent().finish())
+}
+
+async fn reset_attempts(
+    state: web::Data<Arc<MainState>>,
+    web::Path((site, branch, node_id)): web::Path<(String, String, String)>,
+    admin: Admin,
+    body: Option<web::Json<ActionBody>>
+) -> impl Responder {
+    let site_state = site_state(&state, site, branch)?;
+    let node = parse_node(&node_id)?;
+    let body = body.map(web::Json::into_inner).unwrap_or_default();
+    {
+        let mut persistent = lock_known_node(&site_state, node).await?;
+        let node_state = persistent.rollout_state_mut(site_state.config().dry_run).0
+            .get_mut(&node)
+            .ok_or_else(|| actix_web::error::ErrorNotFound("Node has no rollout state"))?;
+        node_state.update_attempts = 0;
+        node_state.update_received = None;
+        node_state.transition(RolloutState::Waiting, chrono::Utc::now());
+        persistent.mark_dirty(node);
+    }
+    site_state.request_save();
+    site_state.refresh.notify();
+    record(&site_state, &admin, Some(node), with_reason("update attempts reset".to_owned(), &body.reason)).await;
+    Ok::<_, actix_web::Error>(HttpResponse::NoContent().finish())
+}
+
+/// Counts the node as having failed as often as `broken-threshold` allows, so it is treated like
+/// any other broken node and `reset-attempts` brings it back
+async fn mark_broken(
+    state: web::Data<Arc<MainState>>,
+    web::Path((site, branch, node_id)): web::Path<(String, String, String)>,
+    admin: Admin,
+    body: Option<web::Json<ActionBody>>
+) -> impl Responder {
+    let site_state = site_state(&state, site, branch)?;
+    let node = parse_node(&node_id)?;
+    let body = body.map(web::Json::into_inner).unwrap_or_default();
+    {
+        let config = site_state.config();
+        let mut persistent = lock_known_node(&site_state, node).await?;
+        let node_state = persistent.rollout_state_mut(config.dry_run).0
+            .entry(node)
+            .or_default();
+        node_state.update_attempts = node_state.update_attempts.max(config.broken_threshold as u32);
+        node_state.update_received = None;
+        node_state.transition(RolloutState::Failed, chrono::Utc::now());
+        persistent.mark_dirty(node);
+    }
+    site_state.request_save();
+    site_state.refresh.notify();
+    record(&site_state, &admin, Some(node), with_reason("marked as broken".to_owned(), &body.reason)).await;
+    Ok::<_, actix_web::Error>(HttpResponse::NoContent().finish())
+}
+
+/// Nothing in the rollout state keeps a node on an old version finished, so this is an override
+async fn mark_finished(
+    state: web::Data<Arc<MainState>>,
+    web::Path((site, branch, node_id)): web::Path<(String, String, String)>,
+    admin: Admin,
+    body: Option<web::Json<ActionBody>>
+) -> impl Responder {
+    let site_state = site_state(&state, site, branch)?;
+    let node = parse_node(&node_id)?;
+    let body = body.map(web::Json::into_inner).unwrap_or_default();
+    store_override(&site_state, &admin, node, UpdatePolicy::Finished, body.reason).await?;
+    Ok::<_, actix_web::Error>(HttpResponse::NoContent().finish())
+}
+
+/// Reloads the configuration file, like SIGHUP does. An invalid configuration is rejected with the
+/// reason and the running one is kept
+async fn reload(
+    state: web::Data<Arc<MainState>>,
+    admin: Admin
+) -> impl Responder {
+    log::info!("Configuration reload requested by {}", admin.name);
+    crate::reload::reload(&state).await
+        .map_err(|e| actix_web::error::ErrorUnprocessableEntity(e.to_string()))?;
+    Ok::<_, actix_web::Error>(HttpResponse::NoContent().finish())
+}
+
+#[test]
+fn test_admin_actions() {
+    use actix_web::{test, App};
+    use actix_web::http::StatusCode;
+    use crate::test_util::{main_state, meshinfo, node, site_config, site_state};
+
+    fn post(action: &str, token: Option<&str>) -> test::TestRequest {
+        let mut req = test::TestRequest::post().uri(&format!("/api/v1/sites/site/stable/{}", action));
+        if let Some(token) = token {
+            req = req.header("Authorization", format!("Bearer {}", token));
+        }
+        req
+    }
+
+    actix_web::rt::System::new("test").block_on(async {
+        let site = site_state(site_config("2.0"), &meshinfo(vec![node(1, "node1", "1.0", None)]));
+        let mut app = test::init_service(
+            App::new()
+                .data(main_state(vec![site.clone()]))
+                .service(web::scope("/api/v1").configure(configure))
+        ).await;
+
+        let response = test::call_service(&mut app, post("pause", None).to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = test::call_service(&mut app, post("pause", Some("wrong")).to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(site.persistent.lock().await.rollout.paused.is_none());
+
+        let response = test::call_service(&mut app, post("resume", Some("secret")).to_request()).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = test::call_service(&mut app, post("pause", Some("secret")).to_request()).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(site.persistent.lock().await.rollout.paused.is_some());
+        assert!(site.graph.read().await.paused);
+
+        // Overrides for nodes which are nowhere to be found are refused
+        let response = test::call_service(&mut app, post("nodes/0000000000ff/mark-finished", Some("secret")).to_request()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = test::call_service(&mut app, post("nodes/000000000001/mark-finished", Some("secret")).to_request()).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = test::call_service(&mut app, post("nodes/0000000000ff/mark-broken", Some("secret")).to_request()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(site.persistent.lock().await.node_state.is_empty());
+    });
+}
@@ -0,0 +1,200 @@
+use actix_web::{web, Responder};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::Arc;
+use crate::{MainState, SiteState};
+use crate::graph::{Graph, NodeKey, UpdatePolicy};
+use crate::mac::MacAddr;
+use crate::node_id::NodeID;
+use crate::persistence::{LinkHistory, NodeState, Override, Pause};
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg
+        .service(
+            web::resource("/sites")
+                .route(web::get().to(list_sites))
+        )
+        .service(
+            web::resource("/sites/{site}/{branch}/nodes")
+                .route(web::get().to(list_nodes))
+        )
+        .service(
+            web::resource("/sites/{site}/{branch}/nodes/{node_id}")
+                .route(web::get().to(node_detail))
+        )
+        .service(
+            web::resource("/sites/{site}/{branch}/explain/{node}")
+                .route(web::get().to(explain))
+        );
+}
+
+#[derive(Serialize)]
+struct SiteInfo {
+    name: String,
+    branch: String,
+    enabled: bool,
+    dry_run: bool,
+    latest_version: String,
+    paused: Option<Pause>,
+    stale: bool,
+    nodes: usize,
+    max_depth: u8
+}
+
+#[derive(Serialize)]
+struct NodeSummary {
+    id: NodeID,
+    hostname: String,
+    mac: MacAddr,
+    addresses: Vec<IpAddr>,
+    model: Option<String>,
+    version: String,
+    online: bool,
+    depth: Option<u8>,
+    policy: Option<UpdatePolicy>
+}
+
+#[derive(Serialize)]
+struct NodeRef {
+    id: NodeID,
+    hostname: String
+}
+
+#[derive(Serialize)]
+struct NodeDetail {
+    #[serde(flatten)]
+    summary: NodeSummary,
+    uplink: Option<NodeRef>,
+    downlinks: Vec<NodeRef>,
+    /// Rollout state of the node, taken from the dry-run state if the site is in dry-run mode
+    state: Option<NodeState>,
+    link_history: Option<LinkHistory>,
+    /// Update policy forced by an administrator
+    #[serde(rename = "override")]
+    node_override: Option<Override>,
+    last_check_in: Option<chrono::DateTime<chrono::Utc>>
+}
+
+/// Only nodes matching every given field are listed
+#[derive(Deserialize)]
+struct NodeFilter {
+    policy: Option<UpdatePolicy>,
+    depth: Option<u8>,
+    model: Option<String>,
+    version: Option<String>,
+    online: Option<bool>
+}
+
+impl NodeFilter {
+    fn matches(&self, summary: &NodeSummary) -> bool {
+        self.policy.map(|p| summary.policy == Some(p)).unwrap_or(true)
+            && self.depth.map(|d| summary.depth == Some(d)).unwrap_or(true)
+            && self.model.as_ref().map(|m| summary.model.as_ref() == Some(m)).unwrap_or(true)
+            && self.version.as_ref().map(|v| summary.version == *v).unwrap_or(true)
+            && self.online.map(|o| summary.online == o).unwrap_or(true)
+    }
+}
+
+fn summarize(graph: &Graph, key: NodeKey) -> NodeSummary {
+    let node = &graph.nodes[key].node;
+    NodeSummary {
+        id: node.node_id,
+        hostname: node.hostname.clone(),
+        mac: node.mac,
+        addresses: node.addresses.clone(),
+        model: node.model.clone(),
+        version: node.firmware.release.clone(),
+        online: node.is_online,
+        depth: graph.depths.get(key).copied(),
+        policy: graph.update_policy.get(key).copied()
+    }
+}
+
+fn node_ref(graph: &Graph, key: NodeKey) -> Option<NodeRef> {
+    graph.nodes.get(key).map(|n| NodeRef {
+        id: n.node.node_id,
+        hostname: n.node.hostname.clone()
+    })
+}
+
+pub fn site_state(state: &MainState, site: String, branch: String) -> Result<Arc<SiteState>, actix_web::Error> {
+    state.site(&site, &branch)
+        .ok_or_else(|| actix_web::error::ErrorNotFound("404 Not Found"))
+}
+
+async fn list_sites(
+    state: web::Data<Arc<MainState>>
+) -> impl Responder {
+    let mut ret = vec![];
+    for site in state.sites() {
+        let config = site.config();
+        let graph = site.graph.read().await;
+        let paused = site.persistent.lock().await.rollout_state(config.dry_run).1.paused.clone();
+        ret.push(SiteInfo {
+            name: config.name.clone(),
+            branch: config.branch.clone(),
+            enabled: config.enabled,
+            dry_run: config.dry_run,
+            latest_version: config.latest_version.clone(),
+            paused,
+            stale: graph.stale,
+            nodes: graph.nodes.len(),
+            max_depth: graph.max_depth
+        });
+    }
+    web::Json(ret)
+}
+
+async fn list_nodes(
+    state: web::Data<Arc<MainState>>,
+    web::Path((site, branch)): web::Path<(String, String)>,
+    filter: web::Query<NodeFilter>
+) -> impl Responder {
+    let site_state = site_state(&state, site, branch)?;
+    let graph = site_state.graph.read().await;
+    let nodes: Vec<_> = graph.nodes.keys()
+        .map(|key| summarize(&graph, key))
+        .filter(|summary| filter.matches(summary))
+        .collect();
+    Ok::<_, actix_web::Error>(web::Json(nodes))
+}
+
+async fn node_detail(
+    state: web::Data<Arc<MainState>>,
+    web::Path((site, branch, node_id)): web::Path<(String, String, String)>
+) -> impl Responder {
+    let site_state = site_state(&state, site, branch)?;
+    let node_id = node_id.parse::<NodeID>()
+        .map_err(|_| actix_web::error::ErrorBadRequest("Invalid node id"))?;
+
+    let graph = site_state.graph.read().await;
+    let key = *graph.node_ids.get(&node_id)
+        .ok_or_else(|| actix_web::error::ErrorNotFound("404 Not Found"))?;
+    let container = &graph.nodes[key];
+
+    let persistent = site_state.persistent.lock().await;
+    let detail = NodeDetail {
+        summary: summarize(&graph, key),
+        uplink: container.uplink.and_then(|uplink| node_ref(&graph, uplink)),
+        downlinks: container.downlinks.iter().filter_map(|downlink| node_ref(&graph, *downlink)).collect(),
+        state: persistent.rollout_state(site_state.config().dry_run).0.get(&node_id).cloned(),
+        link_history: persistent.link_history.get(&node_id).cloned(),
+        node_override: persistent.overrides.get(&node_id).cloned(),
+        last_check_in: site_state.check_ins.lock().await.get(&node_id).copied()
+    };
+    Ok::<_, actix_web::Error>(web::Json(detail))
+}
+
+/// Looks up the node by node id, MAC address or hostname
+async fn explain(
+    state: web::Data<Arc<MainState>>,
+    web::Path((site, branch, node)): web::Path<(String, String, String)>
+) -> impl Responder {
+    let site_state = site_state(&state, site, branch)?;
+    let config = site_state.config();
+    let graph = site_state.graph.read().await;
+    let paused = site_state.persistent.lock().await.rollout_state(config.dry_run).1.paused.clone();
+    let explanation = crate::explain::explain(&graph, &config, paused.as_ref(), &node)
+        .ok_or_else(|| actix_web::error::ErrorNotFound("404 Not Found"))?;
+    Ok::<_, actix_web::Error>(web::Json(explanation))
+}
@@ -0,0 +1,220 @@
+use actix_web::{web, HttpRequest, FromRequest};
+use actix_web::dev::{Payload, PayloadStream};
+use futures::future;
+use serde::{Deserialize, Deserializer};
+use serde::de;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use crate::MainState;
+
+/// An IP network in CIDR notation. A plain address is a network containing only that address
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct Network {
+    addr: IpAddr,
+    prefix_len: u8
+}
+
+impl Network {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, normalize(addr)) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            },
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            },
+            _ => false
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl fmt::Debug for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Network({})", self)
+    }
+}
+
+impl FromStr for Network {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None)
+        };
+        let addr = normalize(addr.parse::<IpAddr>()
+            .map_err(|_| failure::format_err!("invalid network {}", s))?);
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| failure::format_err!("invalid prefix length in network {}", s))?,
+            None => max_len
+        };
+        Ok(Network { addr, prefix_len })
+    }
+}
+
+impl<'de> Deserialize<'de> for Network {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Turns IPv4-mapped IPv6 addresses (`::ffff:192.0.2.1`) into plain IPv4 addresses, as a dual
+/// stack socket reports IPv4 clients that way
+pub fn normalize(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+        IpAddr::V4(_) => addr
+    }
+}
+
+/// Parses an address as found in proxy headers, which may carry a port (`192.0.2.1:4711`,
+/// `[2001:db8::1]:4711`) and be quoted
+fn parse_hop(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    if let Ok(addr) = value.parse() {
+        return Some(addr);
+    }
+    if let Some(rest) = value.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    let (addr, port) = value.rsplit_once(':')?;
+    port.parse::<u16>().ok()?;
+    addr.parse().ok()
+}
+
+/// The `for` addresses of an RFC 7239 `Forwarded` header, from the first hop to the last. Elements
+/// without a `for` parameter (e.g. `proto=https`) are skipped
+fn forwarded_hops(header: &str) -> Vec<Option<IpAddr>> {
+    header.split(',')
+        .filter_map(|element| element.split(';')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+            .map(|(_, value)| parse_hop(value)))
+        .collect()
+}
+
+/// Determines the address of the client. Proxy headers are only believed if the request comes from
+/// a trusted proxy. They are then followed from the right, the last hop which is not a trusted
+/// proxy is the client. `Forwarded` is preferred over `X-Forwarded-For`, unless it does not name
+/// any address.
+///
+/// An unparseable hop (obfuscated identifiers, `unknown`) ends the walk, the proxy which reported
+/// it is taken as the client then.
+pub fn client_addr(
+    peer: IpAddr,
+    forwarded: &[&str],
+    x_forwarded_for: &[&str],
+    trusted: &[Network]
+) -> IpAddr {
+    let is_trusted = |addr: IpAddr| trusted.iter().any(|net| net.contains(addr));
+
+    let mut client = normalize(peer);
+    if !is_trusted(client) {
+        return client;
+    }
+
+    let mut hops: Vec<_> = forwarded.iter().flat_map(|header| forwarded_hops(header)).collect();
+    if hops.is_empty() {
+        hops = x_forwarded_for.iter()
+            .flat_map(|header| header.split(','))
+            .map(parse_hop)
+            .collect();
+    }
+
+    for hop in hops.into_iter().rev() {
+        match hop {
+            Some(addr) => {
+                client = normalize(addr);
+                if !is_trusted(client) {
+                    break;
+                }
+            },
+            None => break
+        }
+    }
+    client
+}
+
+/// The address of the client, determined by `client_addr` with the configured trusted proxies
+pub struct ClientAddr(pub IpAddr);
+
+impl ClientAddr {
+    pub fn of(req: &HttpRequest) -> Result<ClientAddr, actix_web::Error> {
+        let state = req.app_data::<web::Data<Arc<MainState>>>()
+            .ok_or_else(|| actix_web::error::ErrorInternalServerError("Missing state"))?;
+        let peer = req.peer_addr()
+            .ok_or_else(|| actix_web::error::ErrorInternalServerError("Peer address unknown"))?;
+        let headers = |name| req.headers()
+            .get_all(name)
+            .filter_map(|hdr| hdr.to_str().ok())
+            .collect::<Vec<_>>();
+        Ok(ClientAddr(client_addr(
+            peer.ip(),
+            &headers("Forwarded"),
+            &headers("X-Forwarded-For"),
+            &state.trusted_proxies.read().unwrap()
+        )))
+    }
+}
+
+impl FromRequest for ClientAddr {
+    type Error = actix_web::Error;
+    type Future = future::Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload<PayloadStream>) -> Self::Future {
+        future::ready(ClientAddr::of(req))
+    }
+}
+
+#[test]
+fn test_network_contains() {
+    let net: Network = "10.0.0.0/8".parse().unwrap();
+    assert!(net.contains("10.1.2.3".parse().unwrap()));
+    assert!(net.contains("::ffff:10.1.2.3".parse().unwrap()));
+    assert!(!net.contains("11.0.0.1".parse().unwrap()));
+    let net: Network = "2001:db8::/32".parse().unwrap();
+    assert!(net.contains("2001:db8:1::1".parse().unwrap()));
+    assert!(!net.contains("2001:db9::1".parse().unwrap()));
+    assert!("10.0.0.0/33".parse::<Network>().is_err());
+}
+
+#[test]
+fn test_client_addr_chained_proxies() {
+    let trusted = ["127.0.0.1".parse().unwrap(), "10.0.0.0/8".parse().unwrap()];
+    let peer = "127.0.0.1".parse().unwrap();
+    // The left-most entry is set by the client itself and must not be believed
+    assert_eq!(
+        client_addr(peer, &[], &["198.51.100.1, 2001:db8::1", "10.0.0.2"], &trusted),
+        "2001:db8::1".parse::<IpAddr>().unwrap()
+    );
+    assert_eq!(
+        client_addr(peer, &["for=192.0.2.1;proto=https, for=\"[2001:db8::2]:4711\";by=10.0.0.2"], &["198.51.100.1"], &trusted),
+        "2001:db8::2".parse::<IpAddr>().unwrap()
+    );
+    // Headers of untrusted peers are ignored
+    assert_eq!(
+        client_addr("::ffff:192.0.2.7".parse().unwrap(), &[], &["198.51.100.1"], &trusted),
+        "192.0.2.7".parse::<IpAddr>().unwrap()
+    );
+    assert_eq!(client_addr(peer, &["for=unknown"], &[], &trusted), peer);
+    // A `Forwarded` header only carrying other parameters does not hide `X-Forwarded-For`
+    assert_eq!(
+        client_addr(peer, &["proto=https"], &["198.51.100.1"], &trusted),
+        "198.51.100.1".parse::<IpAddr>().unwrap()
+    );
+}
@@ -1,14 +1,79 @@
 use serde::Deserialize;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use crate::storage::Backend;
+use crate::journal::JournalConfig;
+use crate::client_addr::Network;
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
     pub listen: SocketAddr,
+    pub journal: Option<JournalConfig>,
+    /// Bearer tokens accepted by the admin API. Without any, the admin API is unusable
+    #[serde(rename = "admin-tokens", default)]
+    pub admin_tokens: Vec<AdminToken>,
+    /// Proxies whose `Forwarded` and `X-Forwarded-For` headers are believed
+    #[serde(rename = "trusted-proxies", default = "default_trusted_proxies")]
+    pub trusted_proxies: Vec<Network>,
     pub sites: Vec<SiteConfig>
 }
 
 #[derive(Deserialize, Debug, Clone)]
+pub struct AdminToken {
+    /// Who uses the token, recorded with every action
+    pub name: String,
+    pub token: String
+}
+
+impl Config {
+    /// Parses and validates a configuration file
+    pub fn parse(data: &str) -> Result<Config, failure::Error> {
+        let config: Config = toml::from_str(data)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), failure::Error> {
+        for (idx, site) in self.sites.iter().enumerate() {
+            if self.sites[..idx].iter().any(|s| s.name == site.name && s.branch == site.branch) {
+                return Err(failure::format_err!("Site {}/{} is configured twice", site.name, site.branch));
+            }
+            if site.update_dir.is_some() != site.noupdate_dir.is_some() {
+                return Err(failure::format_err!(
+                    "update-dir and noupdate-dir of site {}/{} must be set together",
+                    site.name,
+                    site.branch
+                ));
+            }
+            if site.update_dir.is_none() && (site.on_update.is_empty() || site.on_noupdate.is_empty()) {
+                return Err(failure::format_err!(
+                    "Site {}/{} needs either on-update and on-noupdate or update-dir and noupdate-dir",
+                    site.name,
+                    site.branch
+                ));
+            }
+            if let Some(threshold) = site.circuit_breaker_threshold {
+                if !(0.0..=1.0).contains(&threshold) {
+                    return Err(failure::format_err!(
+                        "circuit-breaker-threshold of site {}/{} must be between 0.0 and 1.0",
+                        site.name,
+                        site.branch
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn site(&self, name: &str, branch: &str) -> Result<&SiteConfig, failure::Error> {
+        self.sites
+            .iter()
+            .find(|s| s.name == name && s.branch == branch)
+            .ok_or_else(|| failure::format_err!("Site {}/{} does not exist", name, branch))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct SiteConfig {
     pub enabled: bool,
     #[serde(rename = "latest-version")]
@@ -16,10 +81,16 @@ pub struct SiteConfig {
     pub name: String,
     pub branch: String,
     pub meshinfo: String,
-    #[serde(rename = "on-update")]
+    #[serde(rename = "on-update", default)]
     pub on_update: String,
-    #[serde(rename = "on-noupdate")]
+    #[serde(rename = "on-noupdate", default)]
     pub on_noupdate: String,
+    /// If set, the files are served from this directory and `noupdate-dir` instead of redirecting
+    /// to `on-update` and `on-noupdate`
+    #[serde(rename = "update-dir")]
+    pub update_dir: Option<PathBuf>,
+    #[serde(rename = "noupdate-dir")]
+    pub noupdate_dir: Option<PathBuf>,
     #[serde(rename = "update-default")]
     pub update_default: bool,
     #[serde(rename = "node-max-age-days")]
@@ -35,5 +106,122 @@ pub struct SiteConfig {
     #[serde(rename = "broken-threshold")]
     pub broken_threshold: u64,
     #[serde(rename = "state-file")]
-    pub state_file: PathBuf
-}
\ No newline at end of file
+    pub state_file: PathBuf,
+    #[serde(rename = "state-backend", default)]
+    pub state_backend: Backend,
+    /// Seconds to wait after a change before writing the state, further changes within that time
+    /// are written together
+    #[serde(rename = "save-interval", default = "default_save_interval")]
+    pub save_interval: u64,
+    /// Number of previous versions of the state file to keep, at most one per hour
+    #[serde(rename = "state-backups", default = "default_state_backups")]
+    pub state_backups: usize,
+    #[serde(rename = "uplink-selection", default)]
+    pub uplink_selection: UplinkSelection,
+    #[serde(rename = "state-retention-days")]
+    pub state_retention_days: Option<u64>,
+    #[serde(rename = "state-archive-file")]
+    pub state_archive_file: Option<PathBuf>,
+    /// Share of failed update attempts (0.0 - 1.0) at which the rollout is paused. If unset, the
+    /// rollout is never paused automatically
+    #[serde(rename = "circuit-breaker-threshold")]
+    pub circuit_breaker_threshold: Option<f64>,
+    #[serde(rename = "circuit-breaker-window", default = "default_circuit_breaker_window")]
+    pub circuit_breaker_window: u64,
+    #[serde(rename = "circuit-breaker-min-samples", default = "default_circuit_breaker_min_samples")]
+    pub circuit_breaker_min_samples: usize,
+    /// Time in seconds a node, which went offline after its update, has to come back after its
+    /// uplink has been updated and is online again, before it is considered lost
+    #[serde(rename = "lost-grace-period", default = "default_lost_grace_period")]
+    pub lost_grace_period: u64,
+    /// Where the last mesh data fetched successfully is kept, defaults to the state file with
+    /// `.meshinfo.json` appended
+    #[serde(rename = "meshinfo-cache")]
+    pub meshinfo_cache: Option<PathBuf>
+}
+
+impl SiteConfig {
+    /// Names the first setting differing from `other`, which can not be changed while the site is
+    /// running as it defines where and how the state is stored
+    pub fn restart_required(&self, other: &SiteConfig) -> Option<&'static str> {
+        if self.state_file != other.state_file {
+            Some("state-file")
+        } else if self.state_backend != other.state_backend {
+            Some("state-backend")
+        } else if self.state_backups != other.state_backups {
+            Some("state-backups")
+        } else if self.save_interval != other.save_interval {
+            Some("save-interval")
+        } else {
+            None
+        }
+    }
+
+    pub fn meshinfo_cache_file(&self) -> PathBuf {
+        self.meshinfo_cache.clone().unwrap_or_else(|| {
+            let mut file = self.state_file.as_os_str().to_owned();
+            file.push(".meshinfo.json");
+            PathBuf::from(file)
+        })
+    }
+}
+
+fn default_trusted_proxies() -> Vec<Network> {
+    vec!["127.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()]
+}
+
+fn default_lost_grace_period() -> u64 {
+    86400
+}
+
+fn default_save_interval() -> u64 {
+    5
+}
+
+fn default_state_backups() -> usize {
+    3
+}
+
+fn default_circuit_breaker_window() -> u64 {
+    86400
+}
+
+fn default_circuit_breaker_min_samples() -> usize {
+    10
+}
+
+/// Which of the recorded uplinks to use for a node that currently reports none
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UplinkSelection {
+    #[default]
+    #[serde(rename = "recent")]
+    Recent,
+    #[serde(rename = "frequent")]
+    Frequent
+}
+
+#[test]
+fn test_reject_duplicate_site() {
+    let site = r#"
+        [[sites]]
+        enabled = true
+        latest-version = "2.0"
+        name = "site"
+        branch = "stable"
+        meshinfo = ""
+        on-update = "/new"
+        on-noupdate = "/old"
+        update-default = false
+        node-max-age-days = 14
+        dry-run = false
+        ignore-autoupdate-off = true
+        refresh-interval = 60
+        update-timeout = 3600
+        broken-threshold = 3
+        state-file = "state.json"
+    "#;
+    let config = format!("listen = \"[::1]:8080\"\n{}", site);
+    assert!(Config::parse(&config).is_ok());
+    let config = format!("listen = \"[::1]:8080\"\n{}{}", site, site);
+    assert!(Config::parse(&config).is_err());
+}
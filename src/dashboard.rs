@@ -0,0 +1,255 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+use crate::{MainState, SiteState};
+use crate::graph::{Graph, NodeKey, UpdatePolicy};
+
+const STYLE: &str = r#"
+body { font-family: sans-serif; margin: 1em 2em; }
+.banner { padding: 0.5em; margin: 0.5em 0; background: #fdd; border: 1px solid #c66; }
+.counters span { display: inline-block; margin-right: 1.5em; }
+.progress { height: 1em; width: 100%; background: #ddd; margin: 0.5em 0 1em 0; display: flex; }
+.progress div { height: 100%; }
+.tree details, .tree .leaf { margin-left: 1.5em; }
+.tree summary, .tree .leaf { padding: 1px 0; }
+.node { padding: 0 0.3em; border-radius: 3px; }
+.pending { background: #fe9; }
+.ready { background: #9cf; }
+.finished { background: #9e9; }
+.broken { background: #f99; }
+.unknown { background: #ddd; }
+.offline { opacity: 0.6; }
+.match { outline: 2px solid #000; }
+.meta { color: #555; font-size: 0.9em; }
+"#;
+
+const SCRIPT: &str = r#"
+function search(text) {
+    text = text.trim().toLowerCase();
+    var first = null;
+    document.querySelectorAll('.node[data-hostname]').forEach(function (node) {
+        var hit = text !== '' && node.dataset.hostname.toLowerCase().indexOf(text) !== -1;
+        node.classList.toggle('match', hit);
+        if (hit) {
+            for (var el = node.parentElement; el; el = el.parentElement) {
+                if (el.tagName === 'DETAILS') { el.open = true; }
+            }
+            if (first === null) { first = node; }
+        }
+    });
+    if (first !== null) { first.scrollIntoView({block: 'center'}); }
+}
+function expand(open) {
+    document.querySelectorAll('.tree details').forEach(function (el) { el.open = open; });
+}
+"#;
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Percent-encodes everything but unreserved characters, so the value can be used as a single
+/// segment of a URL path
+fn path_segment(value: &str) -> String {
+    let mut out = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => out.push(byte as char),
+            _ => {
+                let _ = write!(out, "%{:02X}", byte);
+            }
+        }
+    }
+    out
+}
+
+fn policy_class(policy: Option<UpdatePolicy>) -> &'static str {
+    policy.map(UpdatePolicy::name).unwrap_or("unknown")
+}
+
+fn sorted_by_hostname(graph: &Graph, keys: impl Iterator<Item = NodeKey>) -> Vec<NodeKey> {
+    let mut keys: Vec<_> = keys.collect();
+    keys.sort_by(|a, b| graph.nodes[*a].node.hostname.cmp(&graph.nodes[*b].node.hostname));
+    keys
+}
+
+fn write_node(out: &mut String, graph: &Graph, key: NodeKey, visited: &mut HashSet<NodeKey>) {
+    if !visited.insert(key) {
+        return;
+    }
+    let container = &graph.nodes[key];
+    let node = &container.node;
+    let policy = policy_class(graph.update_policy.get(key).copied());
+    let label = format!(
+        r#"<span class="node {}{}" data-hostname="{}" title="{}">{}</span> <span class="meta">{} · {}{}</span>"#,
+        policy,
+        if node.is_online { "" } else { " offline" },
+        escape(&node.hostname),
+        escape(graph.reasons.get(key).map(String::as_str).unwrap_or_default()),
+        escape(&node.hostname),
+        escape(&node.firmware.release),
+        policy,
+        if node.is_online { "" } else { " · offline" }
+    );
+
+    let children = sorted_by_hostname(graph, container.downlinks.iter().copied());
+    if children.is_empty() {
+        let _ = write!(out, r#"<div class="leaf">{}</div>"#, label);
+    } else {
+        let _ = write!(
+            out,
+            "<details open><summary>{} <span class=\"meta\">({} {})</span></summary>",
+            label,
+            children.len(),
+            if children.len() == 1 { "downlink" } else { "downlinks" }
+        );
+        for child in children {
+            write_node(out, graph, child, visited);
+        }
+        out.push_str("</details>");
+    }
+}
+
+/// Renders the page of one site: counters and the uplink tree from the roots down to the leaves
+pub async fn render_site(site: &SiteState) -> String {
+    let config = site.config();
+    let graph = site.graph.read().await;
+    let pause = site.persistent.lock().await.rollout_state(config.dry_run).1.paused.clone();
+
+    let mut out = String::new();
+    let title = format!("{}/{}", config.name, config.branch);
+    let _ = write!(
+        out,
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Rollout {}</title><style>{}</style><script>{}</script></head><body>",
+        escape(&title),
+        STYLE,
+        SCRIPT
+    );
+    let _ = write!(
+        out,
+        "<h1>Rollout {}</h1><p class=\"meta\">Target version {}, map data of {}</p>",
+        escape(&title),
+        escape(&config.latest_version),
+        graph.meshinfo_timestamp
+    );
+
+    if let Some(pause) = &pause {
+        let _ = write!(out, "<div class=\"banner\">Rollout paused since {}: {}</div>", pause.since, escape(&pause.reason));
+    }
+    if !config.enabled {
+        out.push_str("<div class=\"banner\">Site is disabled, no node is sent the update</div>");
+    }
+    if config.dry_run {
+        out.push_str("<div class=\"banner\">Site is in dry-run mode, no node is actually sent the update</div>");
+    }
+    if graph.stale {
+        out.push_str("<div class=\"banner\">The map is unreachable, this is based on cached map data</div>");
+    }
+
+    let total = graph.nodes.len();
+    let count = |policy| graph.update_policy.values().filter(|p| **p == policy).count();
+    let counts = [
+        (UpdatePolicy::Finished, count(UpdatePolicy::Finished)),
+        (UpdatePolicy::Ready, count(UpdatePolicy::Ready)),
+        (UpdatePolicy::Pending, count(UpdatePolicy::Pending)),
+        (UpdatePolicy::Broken, count(UpdatePolicy::Broken))
+    ];
+    out.push_str("<div class=\"counters\">");
+    for (policy, n) in &counts {
+        let _ = write!(out, "<span><span class=\"node {0}\">{0}</span> {1}</span>", policy_class(Some(*policy)), n);
+    }
+    let _ = write!(out, "<span>total {}</span><span>max depth {}</span></div>", total, graph.max_depth);
+    out.push_str("<div class=\"progress\">");
+    for (policy, n) in &counts {
+        if total > 0 && *n > 0 {
+            let _ = write!(
+                out,
+                "<div class=\"{}\" style=\"width: {:.2}%\"></div>",
+                policy_class(Some(*policy)),
+                *n as f64 * 100.0 / total as f64
+            );
+        }
+    }
+    out.push_str("</div>");
+
+    out.push_str(concat!(
+        "<p><input type=\"search\" placeholder=\"Search hostname\" oninput=\"search(this.value)\"> ",
+        "<button onclick=\"expand(true)\">Expand all</button> ",
+        "<button onclick=\"expand(false)\">Collapse all</button></p>"
+    ));
+
+    out.push_str("<div class=\"tree\">");
+    let mut visited = HashSet::new();
+    let roots = sorted_by_hostname(&graph, graph.nodes.iter().filter(|(_, n)| n.uplink.is_none()).map(|(key, _)| key));
+    for root in roots {
+        write_node(&mut out, &graph, root, &mut visited);
+    }
+    // Nodes whose uplinks form a loop are not reachable from any root
+    let rest = sorted_by_hostname(&graph, graph.nodes.keys().filter(|key| !visited.contains(key)));
+    if !rest.is_empty() {
+        out.push_str("<h2>Not connected to a root</h2>");
+        for key in rest {
+            write_node(&mut out, &graph, key, &mut visited);
+        }
+    }
+    out.push_str("</div></body></html>");
+    out
+}
+
+/// Lists all sites with a link to their page
+pub fn render_index(state: &MainState) -> String {
+    let sites: Vec<_> = state.sites().iter()
+        .map(|site| {
+            let config = site.config();
+            (config.name.clone(), config.branch.clone())
+        })
+        .collect();
+
+    let mut out = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Rollouts</title><style>{}</style></head><body><h1>Rollouts</h1><ul>",
+        STYLE
+    );
+    for (name, branch) in sites {
+        let _ = write!(
+            out,
+            "<li><a href=\"/dashboard/{}/{}\">{}/{}</a></li>",
+            path_segment(&name),
+            path_segment(&branch),
+            escape(&name),
+            escape(&branch)
+        );
+    }
+    out.push_str("</ul></body></html>");
+    out
+}
+
+#[test]
+fn test_render_index() {
+    use crate::test_util::{main_state, meshinfo, site_config, site_state};
+
+    let mut config = site_config("2.0");
+    config.name = "a b/<c>".to_owned();
+    let state = main_state(vec![site_state(config, &meshinfo(vec![]))]);
+    let page = render_index(&state);
+    assert!(page.contains("<a href=\"/dashboard/a%20b%2F%3Cc%3E/stable\">a b/&lt;c&gt;/stable</a>"));
+}
+
+#[tokio::test]
+async fn test_render_site() {
+    use crate::test_util::{meshinfo, node, site_config, site_state};
+
+    let site = site_state(site_config("2.0"), &meshinfo(vec![
+        node(1, "gateway", "2.0", None),
+        node(2, "<script>", "1.0", Some(1))
+    ]));
+    let page = render_site(&site).await;
+    assert!(page.contains("<h1>Rollout site/stable</h1>"));
+    assert!(page.contains("data-hostname=\"&lt;script&gt;\""));
+    assert!(!page.contains("<script>\""));
+    // The node is shown below its uplink
+    let uplink = page.find("data-hostname=\"gateway\"").unwrap();
+    assert!(page[uplink..].contains("(1 downlink)"));
+}
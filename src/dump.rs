@@ -4,15 +4,30 @@ use serde::Serialize;
 use crate::MainState;
 use std::collections::HashMap;
 use crate::graph::UpdatePolicy;
+use crate::persistence::{Pause, LostNode, RolloutState};
+use crate::storage::SaveStatsSnapshot;
 
 #[derive(Serialize, Default)]
 pub struct SiteDump {
+    dry_run: bool,
+    /// The graph has been built from cached mesh data
+    stale: bool,
+    paused: Option<Pause>,
+    state_saves: Option<SaveStatsSnapshot>,
     counts: NodeCounts,
     updated: Vec<NodeInfo>,
     pending: Vec<NodeInfo>,
     failed: Vec<NodeInfo>,
     scheduled: Vec<NodeInfo>,
-    broken: Vec<NodeInfo>
+    broken: Vec<NodeInfo>,
+    lost: Vec<LostNodeInfo>
+}
+
+#[derive(Serialize)]
+struct LostNodeInfo {
+    id: NodeID,
+    #[serde(flatten)]
+    info: LostNode
 }
 
 #[derive(Serialize)]
@@ -20,7 +35,20 @@ struct NodeInfo {
     id: NodeID,
     hostname: String,
     update_fail_count: u32,
-    updated_at: Option<chrono::DateTime<chrono::Utc>>
+    updated_at: Option<chrono::DateTime<chrono::Utc>>,
+    state: RolloutState,
+    state_since: Option<chrono::DateTime<chrono::Utc>>,
+    /// What happened to the node during a dry run, if anything
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dry_run: Option<DryRunInfo>
+}
+
+#[derive(Serialize)]
+struct DryRunInfo {
+    update_fail_count: u32,
+    would_have_updated_at: Option<chrono::DateTime<chrono::Utc>>,
+    state: RolloutState,
+    state_since: Option<chrono::DateTime<chrono::Utc>>
 }
 
 #[derive(Serialize, Default)]
@@ -29,26 +57,38 @@ struct NodeCounts {
     pending: u32,
     failed: u32,
     scheduled: u32,
-    broken: u32
+    broken: u32,
+    lost: u32
 }
 
 pub async fn generate(state: &MainState) -> HashMap<String, SiteDump> {
     let mut ret = HashMap::new();
-    for ((site_name, branch), site) in &state.graphs {
+    for site in state.sites() {
+        let config = site.config();
         let mut site_ret = SiteDump::default();
         let graph = site.graph.read().await;
         let persistent = site.persistent.lock().await;
         for (key, node) in &graph.nodes {
             let node_state = persistent.node_state.get(&node.node.node_id);
+            let dry_run_state = persistent.dry_run.node_state.get(&node.node.node_id);
+            let active_state = if config.dry_run { dry_run_state } else { node_state };
             let info = NodeInfo {
-                id: node.node.node_id.clone(),
+                id: node.node.node_id,
                 hostname: node.node.hostname.clone(),
                 update_fail_count: node_state.map(|s| s.update_attempts).unwrap_or(0),
-                updated_at: node_state.and_then(|s| s.update_received)
+                updated_at: node_state.and_then(|s| s.update_received),
+                state: node_state.map(|s| s.state).unwrap_or_default(),
+                state_since: node_state.and_then(|s| s.state_since()),
+                dry_run: dry_run_state.map(|s| DryRunInfo {
+                    update_fail_count: s.update_attempts,
+                    would_have_updated_at: s.update_received,
+                    state: s.state,
+                    state_since: s.state_since()
+                })
             };
             match graph.update_policy.get(key) {
                 Some(UpdatePolicy::Ready) => {
-                    if info.update_fail_count > 0 {
+                    if active_state.map(|s| s.update_attempts).unwrap_or(0) > 0 {
                         site_ret.failed.push(info);
                     } else {
                         site_ret.scheduled.push(info);
@@ -71,14 +111,27 @@ pub async fn generate(state: &MainState) -> HashMap<String, SiteDump> {
                 }
             }
         }
+        for (id, node_state) in persistent.rollout_state(config.dry_run).0 {
+            if let Some(lost) = &node_state.lost {
+                site_ret.lost.push(LostNodeInfo {
+                    id: *id,
+                    info: lost.clone()
+                });
+            }
+        }
         site_ret.counts = NodeCounts {
             updated: site_ret.updated.len() as u32,
             pending: site_ret.pending.len() as u32,
             failed: site_ret.failed.len() as u32,
             scheduled: site_ret.scheduled.len() as u32,
-            broken: site_ret.broken.len() as u32
+            broken: site_ret.broken.len() as u32,
+            lost: site_ret.lost.len() as u32
         };
-        ret.insert(format!("{}_{}", site_name, branch), site_ret);
+        site_ret.dry_run = config.dry_run;
+        site_ret.stale = graph.stale;
+        site_ret.state_saves = Some(site.save_stats.snapshot());
+        site_ret.paused = persistent.rollout_state(config.dry_run).1.paused.clone();
+        ret.insert(format!("{}_{}", config.name, config.branch), site_ret);
     }
     ret
 }
\ No newline at end of file
@@ -0,0 +1,119 @@
+use serde::Serialize;
+use std::fmt;
+use crate::config::SiteConfig;
+use crate::graph::{Graph, UpdatePolicy};
+use crate::mac::MacAddr;
+use crate::node_id::NodeID;
+use crate::persistence::Pause;
+
+/// Why a node gets the answer it gets when asking for an update
+#[derive(Serialize)]
+pub struct Explanation {
+    pub site: String,
+    pub branch: String,
+    pub node_id: NodeID,
+    pub hostname: String,
+    pub policy: Option<UpdatePolicy>,
+    pub reason: String,
+    /// Whether the node would be sent the update if it asked right now
+    pub update: bool,
+    /// Conditions of the whole site which override the policy of the node
+    pub notes: Vec<String>
+}
+
+/// Looks up a node by node id, MAC address or hostname. Nodes which have been left out of the
+/// graph are found as well.
+pub fn explain(graph: &Graph, config: &SiteConfig, paused: Option<&Pause>, query: &str) -> Option<Explanation> {
+    let mut notes = vec![];
+    if let Some(pause) = paused {
+        notes.push(format!("rollout paused since {}: {}", pause.since, pause.reason));
+    }
+    if !config.enabled {
+        notes.push("site is disabled, no node is sent the update".to_owned());
+    }
+    if config.dry_run {
+        notes.push("site is in dry-run mode, no node is actually sent the update".to_owned());
+    }
+    if graph.stale {
+        notes.push("the map is unreachable, decisions are based on cached mesh data".to_owned());
+    }
+
+    let mac = if query.contains(':') { query.parse::<MacAddr>().ok() } else { None };
+    let key = query.parse::<NodeID>().ok()
+        .and_then(|id| graph.node_ids.get(&id).copied())
+        .or_else(|| mac.and_then(|mac| graph.nodes.iter().find(|(_, n)| n.node.mac == mac).map(|(key, _)| key)))
+        .or_else(|| {
+            graph.nodes.iter()
+                .find(|(_, n)| n.node.hostname.eq_ignore_ascii_case(query))
+                .map(|(key, _)| key)
+        });
+
+    if let Some(key) = key {
+        let node = &graph.nodes[key].node;
+        let policy = graph.update_policy.get(key).copied();
+        let update = paused.is_none() && config.enabled && !config.dry_run && match policy {
+            Some(UpdatePolicy::Ready) | Some(UpdatePolicy::Finished) | Some(UpdatePolicy::Broken) => true,
+            Some(UpdatePolicy::Pending) | None => false
+        };
+        return Some(Explanation {
+            site: config.name.clone(),
+            branch: config.branch.clone(),
+            node_id: node.node_id,
+            hostname: node.hostname.clone(),
+            policy,
+            reason: graph.reasons.get(key).cloned().unwrap_or_else(|| "unknown".to_owned()),
+            update,
+            notes
+        });
+    }
+
+    let excluded = query.parse::<NodeID>().ok()
+        .and_then(|id| graph.excluded.get_key_value(&id))
+        .or_else(|| mac.and_then(|mac| graph.excluded.iter().find(|(_, n)| n.mac == mac)))
+        .or_else(|| graph.excluded.iter().find(|(_, n)| n.hostname.eq_ignore_ascii_case(query)));
+
+    excluded.map(|(id, node)| Explanation {
+        site: config.name.clone(),
+        branch: config.branch.clone(),
+        node_id: *id,
+        hostname: node.hostname.clone(),
+        policy: None,
+        reason: node.reason.clone(),
+        update: config.update_default && paused.is_none() && config.enabled && !config.dry_run,
+        notes
+    })
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} ({}) in {}/{}", self.hostname, self.node_id, self.site, self.branch)?;
+        writeln!(f, "  {}", self.reason)?;
+        for note in &self.notes {
+            writeln!(f, "  note: {}", note)?;
+        }
+        write!(f, "  {}", if self.update { "would be sent the update" } else { "would not be sent the update" })
+    }
+}
+
+#[test]
+fn test_explain_pending_uplink() {
+    use crate::test_util::{meshinfo, node, site_config};
+
+    let config = site_config("2.0");
+    let meshinfo = meshinfo(vec![node(1, "uplink", "1.0", None), node(2, "downlink", "1.0", Some(1))]);
+    let graph = Graph::build(
+        &meshinfo,
+        &config,
+        &mut crate::persistence::PersistentState::default(),
+        &crate::journal::Journal::start(None).0
+    );
+
+    let uplink = explain(&graph, &config, None, "uplink").unwrap();
+    assert_eq!(uplink.policy, Some(UpdatePolicy::Pending));
+    assert_eq!(uplink.reason, "pending: downlink downlink (autoupdater on, version 1.0) must update first");
+    assert!(!uplink.update);
+
+    let downlink = explain(&graph, &config, None, "00:00:00:00:00:02").unwrap();
+    assert_eq!(downlink.policy, Some(UpdatePolicy::Ready));
+    assert!(downlink.update);
+}
@@ -0,0 +1,84 @@
+use serde_json::{json, Value};
+use crate::SiteState;
+use crate::graph::NodeKey;
+
+/// Renders the nodes with a known position as GeoJSON points and their uplinks as line strings
+pub async fn render(site: &SiteState) -> Value {
+    let graph = site.graph.read().await;
+    let persistent = site.persistent.lock().await;
+    let node_states = persistent.rollout_state(site.config().dry_run).0;
+
+    let position = |key: NodeKey| {
+        graph.nodes.get(key)
+            .and_then(|n| n.node.location.as_ref())
+            .map(|l| json!([l.longitude, l.latitude]))
+    };
+
+    let mut features = vec![];
+    for (key, container) in &graph.nodes {
+        let node = &container.node;
+        let coordinates = match position(key) {
+            Some(coordinates) => coordinates,
+            None => continue
+        };
+        let node_state = node_states.get(&node.node_id);
+        features.push(json!({
+            "type": "Feature",
+            "id": node.node_id,
+            "geometry": { "type": "Point", "coordinates": coordinates },
+            "properties": {
+                "kind": "node",
+                "node_id": node.node_id,
+                "hostname": node.hostname,
+                "online": node.is_online,
+                "version": node.firmware.release,
+                "policy": graph.update_policy.get(key),
+                "depth": graph.depths.get(key),
+                "attempts": node_state.map(|s| s.update_attempts).unwrap_or(0),
+                "state": node_state.map(|s| s.state).unwrap_or_default()
+            }
+        }));
+
+        let uplink = container.uplink
+            .and_then(|uplink| position(uplink).map(|coordinates| (&graph.nodes[uplink].node, coordinates)));
+        if let Some((uplink, uplink_coordinates)) = uplink {
+            features.push(json!({
+                "type": "Feature",
+                "geometry": { "type": "LineString", "coordinates": [coordinates, uplink_coordinates] },
+                "properties": {
+                    "kind": "uplink",
+                    "source": node.node_id,
+                    "target": uplink.node_id,
+                    "policy": graph.update_policy.get(key)
+                }
+            }));
+        }
+    }
+
+    json!({
+        "type": "FeatureCollection",
+        "features": features
+    })
+}
+
+#[tokio::test]
+async fn test_render() {
+    use crate::test_util::{meshinfo, node, site_config, site_state};
+
+    let mut gateway = node(1, "gateway", "2.0", None);
+    gateway["location"] = json!({ "longitude": 7.5, "latitude": 51.5 });
+    let mut located = node(2, "located", "1.0", Some(1));
+    located["location"] = json!({ "longitude": 7.6, "latitude": 51.4 });
+    let site = site_state(site_config("2.0"), &meshinfo(vec![gateway, located, node(3, "nowhere", "1.0", Some(1))]));
+
+    let collection = render(&site).await;
+    let features = collection["features"].as_array().unwrap();
+    // Two points and the uplink between them, the node without a position is left out
+    assert_eq!(features.len(), 3);
+    let point = features.iter().find(|f| f["properties"]["hostname"] == "located").unwrap();
+    assert_eq!(point["geometry"]["coordinates"], json!([7.6, 51.4]));
+    assert_eq!(point["properties"]["policy"], "ready");
+    let uplink = features.iter().find(|f| f["properties"]["kind"] == "uplink").unwrap();
+    assert_eq!(uplink["geometry"]["coordinates"], json!([[7.6, 51.4], [7.5, 51.5]]));
+    assert_eq!(uplink["properties"]["target"], "000000000001");
+}
@@ -2,25 +2,39 @@ use crate::meshinfo::MeshInfo;
 use slotmap::{DenseSlotMap, SecondaryMap};
 use std::collections::HashMap;
 use std::net::IpAddr;
-use std::cmp;
 use crate::config::SiteConfig;
-use crate::persistence::PersistentState;
+use crate::persistence::{PersistentState, NodeState, RolloutHealth, LostNode, RolloutState};
+use crate::journal::{Journal, Decision};
+use crate::node_id::NodeID;
+use crate::mac::MacAddr;
+use serde::{Deserialize, Serialize};
 slotmap::new_key_type! { pub struct NodeKey; }
 
 pub struct Graph {
     pub nodes: DenseSlotMap<NodeKey, NodeContainer>,
     pub ip_addrs: HashMap<IpAddr, NodeKey>,
+    pub node_ids: HashMap<NodeID, NodeKey>,
     pub depths: SecondaryMap<NodeKey, u8>,
     pub max_depth: u8,
-    pub deepest_node: Option<NodeKey>,
     pub update_policy: SecondaryMap<NodeKey, UpdatePolicy>,
+    /// Why each node has the update policy it has, for explaining it to node owners
+    pub reasons: SecondaryMap<NodeKey, String>,
+    /// Nodes present in the mesh data, which have been left out of the graph
+    pub excluded: HashMap<NodeID, ExcludedNode>,
+    /// The rollout has been paused, no node should receive the update
+    pub paused: bool,
+    /// Built from cached mesh data because the map could not be reached
+    pub stale: bool,
+    /// When the mesh data has been generated by the map
+    pub meshinfo_timestamp: chrono::DateTime<chrono::Utc>
 }
 
 impl Graph {
-    pub fn build(info: &MeshInfo, config: &SiteConfig, persistent: &mut PersistentState) -> Graph {
+    pub fn build(info: &MeshInfo, config: &SiteConfig, persistent: &mut PersistentState, journal: &Journal) -> Graph {
         let mut nodes = DenseSlotMap::with_capacity_and_key(info.nodes.len());
-        let mut id_lookup = HashMap::<crate::node_id::NodeID, NodeKey>::new();
+        let mut id_lookup = HashMap::<NodeID, NodeKey>::new();
         let mut ip_addrs = HashMap::new();
+        let mut excluded = HashMap::new();
 
         let now = chrono::Utc::now();
         let node_cutoff_time = chrono::Duration::days(config.node_max_age_days as i64);
@@ -36,6 +50,15 @@ impl Graph {
                     node.hostname,
                     config.node_max_age_days
                 );
+                excluded.insert(node.node_id, ExcludedNode {
+                    hostname: node.hostname.clone(),
+                    mac: node.mac,
+                    reason: format!(
+                        "ignored: offline since {}, which is more than node-max-age-days ({})",
+                        node.last_seen,
+                        config.node_max_age_days
+                    )
+                });
                 continue;
             }
 
@@ -45,9 +68,9 @@ impl Graph {
                 downlinks: vec![]
             });
 
-            id_lookup.insert(node.node_id.clone(), key);
+            id_lookup.insert(node.node_id, key);
             for addr in &node.addresses {
-                ip_addrs.insert(addr.clone(), key);
+                ip_addrs.insert(*addr, key);
             }
         }
 
@@ -55,14 +78,14 @@ impl Graph {
         let mut downlinks = SecondaryMap::<NodeKey, Vec<NodeKey>>::new();
         for (key, node) in &mut nodes {
 
-            let stored_uplink = persistent.link_history.get(&node.node.node_id);
+            let stored_uplink = persistent.link_history.get(&node.node.node_id)
+                .and_then(|history| history.preferred(config.uplink_selection));
 
-            let nexthop = &node.node.gateway_nexthop
+            let nexthop = node.node.gateway_nexthop
                 .or_else(|| stored_uplink.map(|su| su.uplink));
 
             if let Some(uplink) = nexthop {
-                let uplink_key = id_lookup.get(uplink)
-                    .map(|key| *key);
+                let uplink_key = id_lookup.get(&uplink).copied();
 
                 if let Some(uplink_key) = uplink_key {
                     node.uplink = Some(uplink_key);
@@ -83,16 +106,52 @@ impl Graph {
         }
 
         let mut update_policy = SecondaryMap::new();
+        let mut reasons = SecondaryMap::new();
         log::debug!("Graph building pass 3: Factoring in if nodes have already received an update and failed at it");
+        if config.dry_run {
+            log::debug!("Site is in dry-run mode, using dry-run state");
+        }
+        let (node_states, rollout) = persistent.rollout_state_mut(config.dry_run);
         process_update_timeouts(
             &mut nodes,
             &mut update_policy,
-            persistent,
-            chrono::Duration::seconds(config.update_timeout as i64),
-            config.broken_threshold as u32,
-            &config.latest_version
+            &mut reasons,
+            node_states,
+            rollout,
+            config,
+            journal
         );
 
+        if let Some(threshold) = config.circuit_breaker_threshold {
+            let tripped = rollout.evaluate(
+                chrono::Duration::seconds(config.circuit_breaker_window as i64),
+                threshold,
+                config.circuit_breaker_min_samples,
+                now
+            );
+            if tripped {
+                let reason = rollout.paused.as_ref().map(|p| p.reason.as_str()).unwrap_or_default();
+                log::error!("Pausing rollout for site {}/{}: {}", config.name, config.branch, reason);
+                journal.record(Decision::Pause, None, None, format!("rollout paused: {}", reason));
+            }
+        }
+
+        // Overrides are in place before the policies are determined, so uplinks of an overridden node
+        // are not held back by it
+        for (key, node) in &nodes {
+            if let Some(node_override) = persistent.overrides.get(&node.node.node_id) {
+                log::trace!("{} has overridden policy {:?}", node.node.hostname, node_override.policy);
+                update_policy.insert(key, node_override.policy);
+                reasons.insert(key, format!(
+                    "{}: set by {} at {}{}",
+                    node_override.policy.name(),
+                    node_override.by,
+                    node_override.at,
+                    node_override.reason.as_ref().map(|r| format!(": {}", r)).unwrap_or_default()
+                ));
+            }
+        }
+
         log::debug!("Graph building pass 4: calculating node depth");
         let mut depths = SecondaryMap::with_capacity(nodes.len());
 
@@ -105,7 +164,7 @@ impl Graph {
             let mut i = 0;
 
             while !node_list.is_empty() {
-                i = i % node_list.len();
+                i %= node_list.len();
                 let key = *node_list.get(i).unwrap();
                 let mut remove = false;
                 {
@@ -147,6 +206,7 @@ impl Graph {
                 continue;
             }
             let mut policy = UpdatePolicy::Ready;
+            let reason;
             if node.node.firmware.release == config.latest_version {
                 log::trace!(
                     "{} is version {} - marking as finished",
@@ -154,8 +214,10 @@ impl Graph {
                     node.node.firmware.release
                 );
                 policy = UpdatePolicy::Finished;
+                reason = format!("finished: runs version {}", node.node.firmware.release);
             } else {
                 log::trace!("{} needs update", node.node.hostname);
+                let mut blocking = vec![];
                 for downlink_key in &node.downlinks {
                     let downlink = nodes.get(*downlink_key).unwrap();
                     let down_pol = update_policy.get(*downlink_key);
@@ -177,16 +239,44 @@ impl Graph {
                                 downlink.node.hostname
                             );
                             policy = UpdatePolicy::Pending;
+                        } else {
+                            continue;
                         }
+                        blocking.push(format!(
+                            "{} (autoupdater {}, version {})",
+                            downlink.node.hostname,
+                            if downlink.node.autoupdater.enabled { "on" } else { "off" },
+                            downlink.node.firmware.release
+                        ));
                     }
                 }
+                reason = if !blocking.is_empty() {
+                    format!(
+                        "pending: {} {} must update first",
+                        if blocking.len() == 1 { "downlink" } else { "downlinks" },
+                        blocking.join(", ")
+                    )
+                } else if node.downlinks.is_empty() {
+                    format!("ready: runs version {} and has no downlinks", node.node.firmware.release)
+                } else {
+                    format!("ready: runs version {} and all downlinks are updated", node.node.firmware.release)
+                };
             }
 
             log::trace!("Host {} has policy {:?}", node.node.hostname, policy);
 
             update_policy.insert(key, policy);
+            reasons.insert(key, reason);
         }
 
+        log::debug!("Graph building pass 6: checking for nodes lost after their update");
+        detect_lost_nodes(
+            &nodes,
+            &update_policy,
+            persistent.rollout_state_mut(config.dry_run).0,
+            chrono::Duration::seconds(config.lost_grace_period as i64)
+        );
+
         if let Some(deepest_node) = deepest_node {
             let node = nodes.get(deepest_node).unwrap();
             log::debug!("Deepest node is {} at a depth of {}", node.node.hostname, max_depth)
@@ -195,10 +285,39 @@ impl Graph {
         Graph {
             nodes,
             ip_addrs,
+            node_ids: id_lookup,
             depths,
             max_depth,
-            deepest_node,
-            update_policy
+            update_policy,
+            reasons,
+            excluded,
+            paused: persistent.rollout_state(config.dry_run).1.paused.is_some(),
+            stale: false,
+            meshinfo_timestamp: info.timestamp
+        }
+    }
+}
+
+impl Graph {
+    /// Records every node whose update policy differs from the one it had in `previous`
+    pub fn journal_policy_changes(&self, previous: &Graph, journal: &Journal) {
+        for (key, node) in &self.nodes {
+            let old = previous.node_ids
+                .get(&node.node.node_id)
+                .and_then(|old_key| previous.update_policy.get(*old_key));
+            let new = self.update_policy.get(key);
+            if old != new {
+                journal.record(
+                    Decision::PolicyChange,
+                    Some(&node.node),
+                    None,
+                    format!(
+                        "{} -> {}",
+                        old.map(|p| format!("{:?}", p)).unwrap_or_else(|| "none".to_owned()),
+                        new.map(|p| format!("{:?}", p)).unwrap_or_else(|| "none".to_owned())
+                    )
+                );
+            }
         }
     }
 }
@@ -206,22 +325,52 @@ impl Graph {
 pub fn process_update_timeouts(
     nodes: &mut DenseSlotMap<NodeKey, NodeContainer>,
     update_policy: &mut SecondaryMap<NodeKey, UpdatePolicy>,
-    pstate: &mut PersistentState,
-    timeout: chrono::Duration,
-    broken_threshold: u32,
-    latest_fw: &str
+    reasons: &mut SecondaryMap<NodeKey, String>,
+    node_states: &mut HashMap<NodeID, NodeState>,
+    rollout: &mut RolloutHealth,
+    config: &SiteConfig,
+    journal: &Journal
 ) {
     let now = chrono::Utc::now();
+    let timeout = chrono::Duration::seconds(config.update_timeout as i64);
+    let broken_threshold = config.broken_threshold as u32;
+    let latest_fw = config.latest_version.as_str();
+    let window = chrono::Duration::seconds(config.circuit_breaker_window as i64);
     for (key, node) in nodes {
-        if let Some(node_state) = pstate.node_state.get_mut(&node.node.node_id) {
-            if let Some(updated_at) = node_state.update_received.clone() {
-                // The host has recently been update
-                if now - updated_at > timeout {
-                    if node.node.is_online {
-                        if node.node.firmware.release != latest_fw {
+        if let Some(node_state) = node_states.get_mut(&node.node.node_id) {
+            let timed_out = node_state.update_received
+                .map(|updated_at| now - updated_at > timeout)
+                .unwrap_or(false);
+            let updated = node.node.firmware.release == latest_fw;
+
+            match node_state.state {
+                RolloutState::Served
+                | RolloutState::Flashing
+                | RolloutState::Assumed
+                | RolloutState::Lost => {
+                    if node.node.is_online && updated {
+                        log::trace!("Node {} is confirmed to run the latest version", node.node.hostname);
+                        node_state.transition(RolloutState::Confirmed, now);
+                        rollout.record(node.node.node_id, true, now, window);
+                    } else if node.node.is_online {
+                        if timed_out {
                             // Node has failed to update, increase counter
                             node_state.update_received = None;
                             node_state.update_attempts += 1;
+                            node_state.transition(RolloutState::Failed, now);
+                            rollout.record(node.node.node_id, false, now, window);
+                            journal.record(
+                                Decision::UpdateFailed,
+                                Some(&node.node),
+                                None,
+                                format!(
+                                    "came back with version {} instead of {}, attempt {} of {}",
+                                    node.node.firmware.release,
+                                    latest_fw,
+                                    node_state.update_attempts,
+                                    broken_threshold
+                                )
+                            );
                             log::trace!(
                                 "Node {} has failed update {} times",
                                 node.node.hostname,
@@ -229,6 +378,7 @@ pub fn process_update_timeouts(
                             );
                             if node_state.update_attempts >= broken_threshold {
                                 update_policy.insert(key, UpdatePolicy::Broken);
+                                reasons.insert(key, broken_reason(node_state));
                                 log::warn!(
                                     "Node {} has failed update {} times and is now considered broken",
                                     node.node.hostname,
@@ -236,33 +386,132 @@ pub fn process_update_timeouts(
                                 );
                             } else {
                                 update_policy.insert(key, UpdatePolicy::Ready);
+                                reasons.insert(key, format!(
+                                    "ready: came back with version {}, retrying (attempt {} of {} failed)",
+                                    node.node.firmware.release,
+                                    node_state.update_attempts,
+                                    broken_threshold
+                                ));
                             }
                         }
-                    } else {
-                        log::trace!(
-                            "Node {} gone offline for extended time, assuming it was successful",
-                            node.node.hostname
-                        );
+                    } else if timed_out {
+                        if node_state.state != RolloutState::Lost {
+                            log::trace!(
+                                "Node {} gone offline for extended time, assuming it was successful",
+                                node.node.hostname
+                            );
+                            node_state.transition(RolloutState::Assumed, now);
+                        }
                         // Node is still offline, assume it was successful
                         update_policy.insert(key, UpdatePolicy::Finished);
+                        reasons.insert(key, if node_state.state == RolloutState::Lost {
+                            "finished: offline since receiving the update, did not come back after its uplink was updated and might be lost".to_owned()
+                        } else {
+                            format!(
+                                "finished: offline since receiving the update more than {} seconds ago, assumed to be updated",
+                                config.update_timeout
+                            )
+                        });
+                    } else {
+                        node_state.transition(RolloutState::Flashing, now);
                     }
+                },
+                RolloutState::Failed => {
+                    if node_state.update_attempts >= broken_threshold {
+                        update_policy.insert(key, UpdatePolicy::Broken);
+                        reasons.insert(key, broken_reason(node_state));
+                    }
+                },
+                RolloutState::Confirmed => {
+                    if node.node.is_online && !updated {
+                        // A newer target version is rolled out, the node has to be served again
+                        node_state.update_received = None;
+                        node_state.transition(RolloutState::Waiting, now);
+                    }
+                },
+                RolloutState::Waiting => {}
+            }
+        }
+    }
+}
+
+fn broken_reason(node_state: &NodeState) -> String {
+    format!("broken: {} update attempts failed", node_state.update_attempts)
+}
+
+/// Checks whether nodes which went offline after receiving the update come back once their uplink
+/// has been updated, as they should then be able to reconnect
+pub fn detect_lost_nodes(
+    nodes: &DenseSlotMap<NodeKey, NodeContainer>,
+    update_policy: &SecondaryMap<NodeKey, UpdatePolicy>,
+    node_states: &mut HashMap<NodeID, NodeState>,
+    grace_period: chrono::Duration
+) {
+    let now = chrono::Utc::now();
+    for (_, node) in nodes {
+        if let Some(node_state) = node_states.get_mut(&node.node.node_id) {
+            if node.node.is_online {
+                if node_state.lost.take().is_some() {
+                    log::info!("Node {}, which was considered lost, is back online", node.node.hostname);
                 }
-            } else {
-                if node_state.update_attempts >= broken_threshold {
-                    update_policy.insert(key, UpdatePolicy::Broken);
-                }
+                node_state.uplink_back_since = None;
+                continue;
+            }
+
+            if node_state.state != RolloutState::Assumed {
+                continue;
+            }
+
+            let uplink = node.uplink.and_then(|key| nodes.get(key).map(|n| (key, n)));
+            let uplink_back = uplink
+                .map(|(key, n)| {
+                    n.node.is_online && update_policy.get(key) == Some(&UpdatePolicy::Finished)
+                })
+                .unwrap_or(false);
+
+            if !uplink_back {
+                node_state.uplink_back_since = None;
+                continue;
+            }
+
+            let back_since = *node_state.uplink_back_since.get_or_insert(now);
+            if now - back_since > grace_period {
+                log::warn!(
+                    "Node {} did not come back after its uplink was updated, it might be bricked. Owner: {}, Location: {}",
+                    node.node.hostname,
+                    node.node.owner.as_deref().unwrap_or("unknown"),
+                    node.node.location.as_ref()
+                        .map(|l| format!("{}, {}", l.latitude, l.longitude))
+                        .unwrap_or_else(|| "unknown".to_owned())
+                );
+                node_state.transition(RolloutState::Lost, now);
+                node_state.lost = Some(LostNode {
+                    since: now,
+                    hostname: node.node.hostname.clone(),
+                    owner: node.node.owner.clone(),
+                    location: node.node.location.clone(),
+                    last_seen: node.node.last_seen,
+                    uplink: uplink.map(|(_, n)| n.node.node_id)
+                });
             }
         }
     }
 }
 
+pub struct ExcludedNode {
+    pub hostname: String,
+    pub mac: MacAddr,
+    pub reason: String
+}
+
 pub struct NodeContainer {
     pub node: crate::meshinfo::Node,
     pub uplink: Option<NodeKey>,
     pub downlinks: Vec<NodeKey>
 }
 
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
 pub enum UpdatePolicy {
     /// A Router cannot be updated yet, as it is waiting for downlinks to finish
     Pending,
@@ -273,4 +522,90 @@ pub enum UpdatePolicy {
     Finished,
     /// A router which has had multiple updates fail and will just be ignored
     Broken
-}
\ No newline at end of file
+}
+
+impl UpdatePolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            UpdatePolicy::Pending => "pending",
+            UpdatePolicy::Ready => "ready",
+            UpdatePolicy::Finished => "finished",
+            UpdatePolicy::Broken => "broken"
+        }
+    }
+}
+
+#[test]
+fn test_new_target_after_confirmed() {
+    use crate::persistence::PersistentState;
+    use crate::test_util::{meshinfo, node, site_config};
+
+    let journal = Journal::start(None).0;
+    let node_id: NodeID = "000000000001".parse().unwrap();
+    let mut state = PersistentState::default();
+
+    state.update_node(&node_id, false);
+    Graph::build(&meshinfo(vec![node(1, "node", "2.0", None)]), &site_config("2.0"), &mut state, &journal);
+    assert_eq!(state.node_state[&node_id].state, RolloutState::Confirmed);
+
+    // Rolling out the next version starts over with the confirmed node
+    let config = site_config("3.0");
+    let meshinfo = meshinfo(vec![node(1, "node", "2.0", None)]);
+    Graph::build(&meshinfo, &config, &mut state, &journal);
+    assert_eq!(state.node_state[&node_id].state, RolloutState::Waiting);
+    assert!(state.node_state[&node_id].update_received.is_none());
+
+    state.update_node(&node_id, false);
+    assert_eq!(state.node_state[&node_id].state, RolloutState::Served);
+
+    // Coming back with the old version after the timeout is a failed attempt
+    state.node_state.get_mut(&node_id).unwrap().update_received = Some(chrono::Utc::now() - chrono::Duration::hours(2));
+    let graph = Graph::build(&meshinfo, &config, &mut state, &journal);
+    assert_eq!(state.node_state[&node_id].state, RolloutState::Failed);
+    assert_eq!(state.node_state[&node_id].update_attempts, 1);
+    assert_eq!(graph.update_policy[graph.node_ids[&node_id]], UpdatePolicy::Ready);
+}
+
+#[test]
+fn test_detect_lost_nodes() {
+    use crate::persistence::PersistentState;
+    use crate::test_util::{meshinfo, node, site_config};
+
+    let journal = Journal::start(None).0;
+    let config = site_config("2.0");
+    let node_id: NodeID = "000000000002".parse().unwrap();
+    let grace_period = chrono::Duration::hours(1);
+
+    let mut offline = node(2, "node", "1.0", Some(1));
+    offline["is_online"] = serde_json::json!(false);
+    let graph = Graph::build(
+        &meshinfo(vec![node(1, "uplink", "2.0", None), offline]),
+        &config,
+        &mut PersistentState::default(),
+        &journal
+    );
+
+    let mut node_states = HashMap::new();
+    node_states.insert(node_id, NodeState::default());
+    node_states.get_mut(&node_id).unwrap().transition(RolloutState::Assumed, chrono::Utc::now());
+
+    // The grace period starts once the uplink is back with the new version
+    detect_lost_nodes(&graph.nodes, &graph.update_policy, &mut node_states, grace_period);
+    assert!(node_states[&node_id].uplink_back_since.is_some());
+    assert!(node_states[&node_id].lost.is_none());
+
+    node_states.get_mut(&node_id).unwrap().uplink_back_since = Some(chrono::Utc::now() - chrono::Duration::hours(2));
+    detect_lost_nodes(&graph.nodes, &graph.update_policy, &mut node_states, grace_period);
+    assert_eq!(node_states[&node_id].state, RolloutState::Lost);
+    assert_eq!(node_states[&node_id].lost.as_ref().unwrap().hostname, "node");
+
+    let graph = Graph::build(
+        &meshinfo(vec![node(1, "uplink", "2.0", None), node(2, "node", "2.0", Some(1))]),
+        &config,
+        &mut PersistentState::default(),
+        &journal
+    );
+    detect_lost_nodes(&graph.nodes, &graph.update_policy, &mut node_states, grace_period);
+    assert!(node_states[&node_id].lost.is_none());
+    assert!(node_states[&node_id].uplink_back_since.is_none());
+}
@@ -0,0 +1,243 @@
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::stream::StreamExt;
+use tokio::sync::mpsc;
+use tokio::task;
+use crate::meshinfo::Node;
+use crate::node_id::NodeID;
+use crate::persistence::sibling;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct JournalConfig {
+    pub file: PathBuf,
+    /// Rotate once the journal reaches this size in bytes
+    #[serde(rename = "max-size")]
+    pub max_size: Option<u64>,
+    /// Rotate when the (UTC) date changes
+    #[serde(rename = "rotate-daily", default)]
+    pub rotate_daily: bool
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    /// A node has been sent to the new firmware
+    Update,
+    /// A node has been sent to the old firmware
+    NoUpdate,
+    /// The update policy of a node changed during a refresh
+    PolicyChange,
+    /// A node came back with the old firmware
+    UpdateFailed,
+    /// The rollout has been paused automatically
+    Pause,
+    /// An administrator intervened
+    Admin
+}
+
+#[derive(Serialize, Debug)]
+pub struct JournalEntry {
+    pub at: chrono::DateTime<chrono::Utc>,
+    pub site: String,
+    pub branch: String,
+    /// The site was in dry-run mode, nothing has actually been served
+    pub dry_run: bool,
+    pub decision: Decision,
+    pub node_id: Option<NodeID>,
+    pub hostname: Option<String>,
+    pub client_ip: Option<IpAddr>,
+    pub reason: String
+}
+
+/// Handle for writing to the decision journal. All handles share one writer task, entries are
+/// dropped silently if no journal is configured or it has been closed.
+#[derive(Clone)]
+pub struct Journal {
+    /// Shared by all handles, so `close` drops the sender for every one of them
+    tx: Arc<RwLock<Option<mpsc::UnboundedSender<JournalEntry>>>>,
+    site: String,
+    branch: String,
+    dry_run: bool
+}
+
+impl Journal {
+    /// Starts the writer task. The returned handle finishes once the journal has been closed or
+    /// every `Journal` has been dropped, and all entries are written
+    pub fn start(config: Option<&JournalConfig>) -> (Journal, Option<task::JoinHandle<()>>) {
+        let (tx, handle) = if let Some(config) = config {
+            let (tx, rx) = mpsc::unbounded_channel();
+            (Some(tx), Some(task::spawn(writer(config.clone(), rx))))
+        } else {
+            (None, None)
+        };
+        let journal = Journal {
+            tx: Arc::new(RwLock::new(tx)),
+            site: String::new(),
+            branch: String::new(),
+            dry_run: false
+        };
+        (journal, handle)
+    }
+
+    /// Stops accepting entries for all handles. The writer task finishes once the entries recorded
+    /// so far are written
+    pub fn close(&self) {
+        self.tx.write().unwrap().take();
+    }
+
+    pub fn for_site(&self, site: &str, branch: &str, dry_run: bool) -> Journal {
+        Journal {
+            tx: self.tx.clone(),
+            site: site.to_owned(),
+            branch: branch.to_owned(),
+            dry_run
+        }
+    }
+
+    pub fn record(&self, decision: Decision, node: Option<&Node>, client_ip: Option<IpAddr>, reason: impl Into<String>) {
+        self.record_node(
+            decision,
+            node.map(|n| n.node_id),
+            node.map(|n| n.hostname.clone()),
+            client_ip,
+            reason
+        )
+    }
+
+    pub fn record_node(
+        &self,
+        decision: Decision,
+        node_id: Option<NodeID>,
+        hostname: Option<String>,
+        client_ip: Option<IpAddr>,
+        reason: impl Into<String>
+    ) {
+        if let Some(tx) = &*self.tx.read().unwrap() {
+            let entry = JournalEntry {
+                at: chrono::Utc::now(),
+                site: self.site.clone(),
+                branch: self.branch.clone(),
+                dry_run: self.dry_run,
+                decision,
+                node_id,
+                hostname,
+                client_ip,
+                reason: reason.into()
+            };
+            if tx.send(entry).is_err() {
+                log::error!("Journal writer is gone, dropping entry");
+            }
+        }
+    }
+}
+
+async fn open(file: &Path) -> Result<(fs::File, u64, chrono::NaiveDate), failure::Error> {
+    let out = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file)
+        .await?;
+    let metadata = out.metadata().await?;
+    let modified: chrono::DateTime<chrono::Utc> = metadata.modified()?.into();
+    Ok((out, metadata.len(), modified.naive_utc().date()))
+}
+
+/// Moves the journal aside, named after the current time. A counter is added if the journal has
+/// already been rotated within the same second
+async fn rotate(file: &Path) -> Result<(), failure::Error> {
+    let stamp = chrono::Utc::now().format("%Y%m%d%H%M%S").to_string();
+    let mut rotated = sibling(file, &format!(".{}", stamp));
+    let mut n = 1;
+    while fs::metadata(&rotated).await.is_ok() {
+        rotated = sibling(file, &format!(".{}-{}", stamp, n));
+        n += 1;
+    }
+    log::info!("Rotating journal {:?} to {:?}", file, rotated);
+    fs::rename(file, rotated).await?;
+    Ok(())
+}
+
+async fn writer(config: JournalConfig, mut rx: mpsc::UnboundedReceiver<JournalEntry>) {
+    let mut current = None;
+    while let Some(entry) = rx.next().await {
+        let result: Result<(), failure::Error> = async {
+            let line = serde_json::to_string(&entry)? + "\n";
+
+            if current.is_none() {
+                current = Some(open(&config.file).await?);
+            }
+            let (_, size, date) = current.as_ref().unwrap();
+            let too_big = config.max_size.map(|max| *size > 0 && size + line.len() as u64 > max).unwrap_or(false);
+            let new_day = config.rotate_daily && *size > 0 && *date != entry.at.naive_utc().date();
+            if too_big || new_day {
+                current = None;
+                rotate(&config.file).await?;
+                current = Some(open(&config.file).await?);
+            }
+
+            let (out, size, date) = current.as_mut().unwrap();
+            out.write_all(line.as_bytes()).await?;
+            out.flush().await?;
+            *size += line.len() as u64;
+            *date = entry.at.naive_utc().date();
+            Ok(())
+        }.await;
+
+        if let Err(e) = result {
+            log::error!("Failed to write to journal {:?}: {}", config.file, e);
+            current = None;
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("gluon-update-manager-test-{}-{}", std::process::id(), name));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[tokio::test]
+async fn test_writer_flushes_on_close() {
+    let dir = test_dir("journal");
+    let config = JournalConfig { file: dir.join("journal.jsonl"), max_size: None, rotate_daily: false };
+    let (journal, writer) = Journal::start(Some(&config));
+    let site = journal.for_site("site", "stable", false);
+    for n in 0..10 {
+        site.record_node(Decision::Admin, None, None, None, format!("entry {}", n));
+    }
+    journal.close();
+    // Entries recorded after closing are dropped, the writer must finish anyway
+    site.record_node(Decision::Admin, None, None, None, "too late");
+    writer.unwrap().await.unwrap();
+
+    let written = std::fs::read_to_string(&config.file).unwrap();
+    assert_eq!(written.lines().count(), 10);
+    assert!(written.lines().last().unwrap().contains("entry 9"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_rotate_within_one_second() {
+    let dir = test_dir("rotate");
+    // Every entry is bigger than this, so each one goes to a file of its own
+    let config = JournalConfig { file: dir.join("journal.jsonl"), max_size: Some(10), rotate_daily: false };
+    let (journal, writer) = Journal::start(Some(&config));
+    for n in 0..3 {
+        journal.record_node(Decision::Admin, None, None, None, format!("entry {}", n));
+    }
+    drop(journal);
+    writer.unwrap().await.unwrap();
+
+    let files: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+    assert_eq!(files.len(), 3);
+    let entries: usize = files.iter()
+        .map(|file| std::fs::read_to_string(file).unwrap().lines().count())
+        .sum();
+    assert_eq!(entries, 3);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
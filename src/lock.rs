@@ -0,0 +1,63 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use fs2::FileExt;
+
+/// Advisory lock ensuring only one instance works on a state file. It is held until dropped.
+///
+/// The lock is taken on a separate `.lock` file, as the state file itself gets replaced on every
+/// save. The lock file contains the PID of the holder.
+pub struct StateLock {
+    _file: File
+}
+
+impl StateLock {
+    pub fn acquire(state_file: &Path) -> Result<StateLock, failure::Error> {
+        let mut path = state_file.as_os_str().to_owned();
+        path.push(".lock");
+        let path = PathBuf::from(path);
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        if file.try_lock_exclusive().is_err() {
+            let mut holder = String::new();
+            file.read_to_string(&mut holder)?;
+            let holder = holder.trim();
+            if holder == std::process::id().to_string() {
+                return Err(failure::format_err!(
+                    "State file {:?} is already used by another site of this instance",
+                    state_file
+                ));
+            }
+            return Err(failure::format_err!(
+                "State file {:?} is in use by another instance (PID {}), lock file {:?}",
+                state_file,
+                if holder.is_empty() { "unknown" } else { holder },
+                path
+            ));
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        writeln!(file, "{}", std::process::id())?;
+        file.sync_all()?;
+
+        Ok(StateLock { _file: file })
+    }
+}
+
+#[test]
+fn test_lock_exclusive() {
+    let file = std::env::temp_dir().join(format!("gluon-update-manager-test-{}-lock.json", std::process::id()));
+    let lock = StateLock::acquire(&file).unwrap();
+    let err = StateLock::acquire(&file).err().unwrap();
+    assert!(err.to_string().contains("another site of this instance"));
+    drop(lock);
+    drop(StateLock::acquire(&file).unwrap());
+    std::fs::remove_file(format!("{}.lock", file.display())).unwrap();
+}
@@ -6,10 +6,30 @@ mod config;
 mod graph;
 mod mac;
 mod meshinfo;
-
-use tokio::sync::{mpsc, RwLock, Mutex};
+mod storage;
+mod sqlite;
+mod migration;
+mod journal;
+mod transfer;
+mod lock;
+mod api;
+mod explain;
+mod metrics;
+mod dashboard;
+mod topology;
+mod geojson;
+mod admin;
+mod reload;
+mod client_addr;
+#[cfg(test)]
+mod test_util;
+
+use tokio::sync::{mpsc, RwLock, Mutex, Notify};
 use tokio::{task, fs, time};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use std::path::{Path, PathBuf};
 use crate::config::SiteConfig;
 use sd_notify::NotifyState;
 use std::collections::HashMap;
@@ -17,93 +37,540 @@ use crate::graph::UpdatePolicy;
 use tokio::stream::StreamExt;
 use std::net::SocketAddr;
 use clap::clap_app;
-use crate::persistence::{PersistentState, LinkInfo};
-use std::path::PathBuf;
+use crate::persistence::PersistentState;
+use crate::journal::{Journal, Decision};
+use crate::lock::StateLock;
+use crate::meshinfo::MeshInfo;
+use crate::node_id::NodeID;
 
 pub struct MainState {
-    graphs: HashMap<(String, String), Arc<SiteState>>,
-    listen_addr: SocketAddr
+    /// Replaced on a configuration reload, so it is only locked briefly to look up a site
+    graphs: std::sync::RwLock<HashMap<(String, String), Arc<SiteState>>>,
+    listen_addr: SocketAddr,
+    admin_tokens: std::sync::RwLock<Vec<config::AdminToken>>,
+    trusted_proxies: std::sync::RwLock<Vec<client_addr::Network>>,
+    config_file: PathBuf,
+    journal: Journal,
+    state_tx: mpsc::Sender<()>,
+    /// Held while a configuration reload is in progress
+    reloading: Mutex<()>
+}
+
+impl MainState {
+    pub fn site(&self, name: &str, branch: &str) -> Option<Arc<SiteState>> {
+        self.graphs.read().unwrap().get(&(name.to_owned(), branch.to_owned())).cloned()
+    }
+
+    /// All sites, sorted by name and branch
+    pub fn sites(&self) -> Vec<Arc<SiteState>> {
+        let graphs = self.graphs.read().unwrap();
+        let mut keys: Vec<_> = graphs.keys().collect();
+        keys.sort();
+        keys.into_iter().map(|key| graphs[key].clone()).collect()
+    }
 }
 
 pub struct SiteState {
     graph: RwLock<graph::Graph>,
     persistent: Arc<Mutex<persistence::PersistentState>>,
     persistent_saver: mpsc::Sender<()>,
-    config: SiteConfig
+    storage: Arc<dyn storage::Storage>,
+    save_stats: Arc<storage::SaveStats>,
+    /// Configuration and journal of the site, replaced together on a configuration reload
+    settings: std::sync::RwLock<(Arc<SiteConfig>, Journal)>,
+    /// When each node last asked for an update
+    check_ins: Mutex<HashMap<NodeID, chrono::DateTime<chrono::Utc>>>,
+    metrics: metrics::SiteMetrics,
+    /// Wakes up the configurator task to refresh the graph right away
+    refresh: Notify,
+    /// Set once the site has been removed from the configuration, stops its background task
+    retired: AtomicBool,
+    /// Makes the persistent state saver write the state a last time and release the state lock
+    stop_saver: Arc<Notify>,
+    saver: Mutex<Option<task::JoinHandle<Result<(), failure::Error>>>>
+}
+
+impl SiteState {
+    /// The current configuration. Keep the returned value for the duration of a request, so it
+    /// sees a consistent configuration across a reload
+    pub fn config(&self) -> Arc<SiteConfig> {
+        self.settings.read().unwrap().0.clone()
+    }
+
+    pub fn journal(&self) -> Journal {
+        self.settings.read().unwrap().1.clone()
+    }
+
+    /// Applies a changed configuration. The storage of the site stays as it is
+    pub fn set_config(&self, config: SiteConfig, journal: &Journal) {
+        let site_journal = journal.for_site(&config.name, &config.branch, config.dry_run);
+        *self.settings.write().unwrap() = (Arc::new(config), site_journal);
+    }
+
+    /// Schedules writing the persistent state. Does not wait, if a save is already pending the
+    /// change will be written with it
+    pub fn request_save(&self) {
+        self.save_stats.requested.fetch_add(1, Ordering::Relaxed);
+        if let Err(mpsc::error::TrySendError::Closed(_)) = self.persistent_saver.clone().try_send(()) {
+            if self.retired.load(Ordering::SeqCst) {
+                return;
+            }
+            let config = self.config();
+            log::error!(
+                "Persistent state saver for site {}/{} is gone, state is not written",
+                config.name,
+                config.branch
+            );
+        }
+    }
+
+    /// Writes the persistent state right away
+    pub async fn save_now(&self) {
+        save(&self.persistent, &*self.storage, &self.save_stats).await
+    }
+
+    /// Stops the background tasks of a site, which has been removed from the configuration. Returns
+    /// once the state has been written and the state lock is released
+    pub async fn retire(&self) {
+        self.retired.store(true, Ordering::SeqCst);
+        self.refresh.notify();
+        self.stop_saver.notify();
+        if let Some(saver) = self.saver.lock().await.take() {
+            if let Ok(Err(e)) = saver.await {
+                log::error!("Persistent state saver failed: {}", e);
+            }
+        }
+    }
 }
 
 fn args<'a, 'b>() -> clap::App<'a, 'b> {
     clap_app!(gluon_update_manager =>
         (author: "Stephan Henrichs <kilobyte+gluon-update-mgr@kilobyte22.de>")
         (@arg config: -c --config +takes_value +required "Config File")
+        (@subcommand resume =>
+            (about: "Resumes a rollout which has been paused. The service must not be running")
+            (@arg site: +required "Site name")
+            (@arg branch: +required "Branch name")
+        )
+        (@subcommand export =>
+            (about: "Exports the state of a site as a portable JSON document")
+            (@arg site: +required "Site name")
+            (@arg branch: +required "Branch name")
+            (@arg output: -o --output +takes_value "Output file, defaults to stdout")
+        )
+        (@subcommand explain =>
+            (about: "Explains why a node is or is not sent the update")
+            (@arg site: +required "Site name")
+            (@arg branch: +required "Branch name")
+            (@arg node: +required "Node id, MAC address or hostname")
+        )
+        (@subcommand graph =>
+            (about: "Writes the uplink tree of a site as Graphviz DOT or GraphML")
+            (@arg site: +required "Site name")
+            (@arg branch: +required "Branch name")
+            (@arg format: -f --format +takes_value possible_value[dot graphml] "Output format, defaults to dot")
+            (@arg output: -o --output +takes_value "Output file, defaults to stdout")
+        )
+        (@subcommand import =>
+            (about: "Merges an exported state into the state of a site. The service must not be running")
+            (@arg site: +required "Site name")
+            (@arg branch: +required "Branch name")
+            (@arg input: +required "Exported state file")
+        )
     )
 }
 
-async fn generate_graph(
-    config: &SiteConfig,
-    persistent: &mut PersistentState
-) -> Result<graph::Graph, failure::Error> {
-    let meshinfo = reqwest::get(&config.meshinfo)
+/// Fetches the mesh data of a site, returning it parsed and as received
+async fn download_meshinfo(config: &SiteConfig) -> Result<(MeshInfo, String), failure::Error> {
+    let data = reqwest::get(&config.meshinfo)
         .await?
-        .json()
+        .error_for_status()?
+        .text()
         .await?;
+    Ok((serde_json::from_str(&data)?, data))
+}
 
-    let graph = graph::Graph::build(&meshinfo, config, persistent);
+/// Fetches the mesh data of a site and keeps a copy of it, so the site can start up while the
+/// map is unreachable
+async fn fetch_meshinfo(config: &SiteConfig) -> Result<MeshInfo, failure::Error> {
+    let (meshinfo, data) = download_meshinfo(config).await?;
+
+    let cache_file = config.meshinfo_cache_file();
+    let mut tmp_file = cache_file.as_os_str().to_owned();
+    tmp_file.push(".tmp");
+    let result = async {
+        fs::write(&tmp_file, &data).await?;
+        fs::rename(&tmp_file, &cache_file).await
+    }.await;
+    if let Err(e) = result {
+        log::warn!("Failed to write mesh data cache {:?}: {}", cache_file, e);
+    }
 
+    Ok(meshinfo)
+}
+
+async fn load_cached_meshinfo(config: &SiteConfig) -> Result<MeshInfo, failure::Error> {
+    Ok(serde_json::from_str(&fs::read_to_string(config.meshinfo_cache_file()).await?)?)
+}
+
+/// Falls back to the cached mesh data after fetching it failed with `error`
+async fn fall_back_to_cache(config: &SiteConfig, error: failure::Error) -> Result<MeshInfo, failure::Error> {
+    let meshinfo = load_cached_meshinfo(config).await.map_err(|cache_error| failure::format_err!(
+        "Failed to fetch mesh data ({}) and no cached mesh data is available ({})",
+        error,
+        cache_error
+    ))?;
+    log::warn!(
+        "Failed to fetch mesh data for site {}/{}: {}. Starting from cached mesh data of {}",
+        config.name,
+        config.branch,
+        error,
+        meshinfo.timestamp
+    );
+    Ok(meshinfo)
+}
+
+/// Builds the graph the site starts with. If the map is unreachable, the last cached mesh data is
+/// used instead. Such a graph is marked stale and built against a copy of the persistent state, as
+/// the outdated data must not advance timeouts or uplink history.
+async fn initial_graph(
+    config: &SiteConfig,
+    persistent: &Mutex<PersistentState>,
+    journal: &Journal
+) -> Result<graph::Graph, failure::Error> {
+    let error = match fetch_meshinfo(config).await {
+        Ok(meshinfo) => return Ok(generate_graph(config, &meshinfo, persistent, journal).await),
+        Err(e) => e
+    };
+
+    let meshinfo = fall_back_to_cache(config, error).await?;
+    // Decisions taken on the discarded state are taken again with the first refresh, journaling
+    // them now would record them twice
+    let mut graph = graph::Graph::build(&meshinfo, config, &mut persistent.lock().await.clone(), &Journal::start(None).0);
+    graph.stale = true;
+    Ok(graph)
+}
+
+/// Rebuilds the graph of a site from its cached mesh data, so a changed configuration applies
+/// without waiting for the map. Like a stale graph, it is built against a copy of the state
+async fn rebuild_from_cache(site: &SiteState) -> Result<(), failure::Error> {
+    let config = site.config();
+    let meshinfo = load_cached_meshinfo(&config).await?;
+    let mut persistent = site.persistent.lock().await.clone();
+    let mut new_graph = graph::Graph::build(&meshinfo, &config, &mut persistent, &Journal::start(None).0);
+    let mut graph = site.graph.write().await;
+    new_graph.stale = graph.stale;
+    new_graph.paused = site.persistent.lock().await.rollout_state(config.dry_run).1.paused.is_some();
+    *graph = new_graph;
+    Ok(())
+}
+
+/// Builds the graph from freshly fetched mesh data and records what has been seen in the
+/// persistent state. The graph is built against a copy of the state, which is applied afterwards,
+/// so update checks do not wait for the build or for archiving pruned nodes.
+async fn generate_graph(
+    config: &SiteConfig,
+    meshinfo: &MeshInfo,
+    state: &Mutex<PersistentState>,
+    journal: &Journal
+) -> graph::Graph {
+    let before = state.lock().await.clone();
+    let mut persistent = before.clone();
+    let graph = graph::Graph::build(meshinfo, config, &mut persistent, journal);
+
+    // Only uplinks actually reported by the map are recorded, uplinks which were taken from the
+    // history would otherwise reinforce themselves
     let now = chrono::Utc::now();
     for (_, node) in &graph.nodes {
-        if !persistent.link_history.contains_key(&node.node.node_id) {
-            if let Some(uplink_key) = &node.uplink {
-                if let Some(uplink_node) = graph.nodes.get(*uplink_key) {
-                    persistent.link_history.insert(node.node.node_id.clone(), LinkInfo {
-                        uplink: uplink_node.node.node_id.clone(),
-                        since: now
-                    });
+        if let Some(nexthop) = node.node.gateway_nexthop {
+            if let Some(uplink_node) = node.uplink.and_then(|key| graph.nodes.get(key)) {
+                if uplink_node.node.node_id == nexthop {
+                    persistent.record_uplink(node.node.node_id, nexthop, now);
                 }
             }
         }
     }
 
-    Ok(graph)
+    for node in &meshinfo.nodes {
+        persistent.mark_seen(node.node_id, now);
+    }
+
+    if let Some(retention_days) = config.state_retention_days {
+        prune_persistent_state(config, &mut persistent, now - chrono::Duration::days(retention_days as i64)).await;
+    }
+
+    state.lock().await.apply_changes(&before, persistent);
+    graph
+}
+
+async fn prune_persistent_state(
+    config: &SiteConfig,
+    persistent: &mut PersistentState,
+    cutoff: chrono::DateTime<chrono::Utc>
+) {
+    let stale = persistent.stale_nodes(cutoff);
+    if stale.is_empty() {
+        return;
+    }
+
+    if let Some(archive_file) = &config.state_archive_file {
+        let pruned: Vec<_> = stale.iter()
+            .map(|node| persistence::PrunedNode {
+                node_id: *node,
+                pruned_at: chrono::Utc::now(),
+                last_seen: persistent.last_seen.get(node).copied(),
+                node_state: persistent.node_state.get(node).cloned(),
+                dry_run_state: persistent.dry_run.node_state.get(node).cloned(),
+                link_history: persistent.link_history.get(node).cloned()
+            })
+            .collect();
+        if let Err(e) = persistence::archive(archive_file, &pruned).await {
+            log::error!(
+                "Failed to archive pruned state of site {}/{} to {:?}, not pruning: {}",
+                config.name,
+                config.branch,
+                archive_file,
+                e
+            );
+            return;
+        }
+    }
+
+    let pruned = persistent.prune(&stale);
+    log::info!(
+        "Pruned {} nodes from state of site {}/{} ({} node states, {} link histories), {} nodes remaining",
+        pruned.len(),
+        config.name,
+        config.branch,
+        pruned.iter().filter(|p| p.node_state.is_some()).count(),
+        pruned.iter().filter(|p| p.link_history.is_some()).count(),
+        persistent.last_seen.len()
+    );
 }
 
 async fn configurator_task(
     site: Arc<SiteState>,
     mut updater: mpsc::Sender<()>
 ) -> Result<(), failure::Error> {
-    let mut persistent_saver = site.persistent_saver.clone();
     loop {
-        time::delay_for(time::Duration::from_secs(site.config.refresh_interval)).await;
-
-        log::debug!("Refreshing node graph for site {}/{}", site.config.name, site.config.branch);
-        match generate_graph(&site.config, &mut *site.persistent.lock().await).await {
-            Ok(new_graph) => {
-                persistent_saver.send(()).await?;
+        tokio::select! {
+            _ = time::delay_for(time::Duration::from_secs(site.config().refresh_interval)) => {},
+            _ = site.refresh.notified() => {}
+        }
+        if site.retired.load(Ordering::SeqCst) {
+            return Ok(());
+        }
 
-                let mut graph = site.graph.write().await;
-                *graph = new_graph;
-                updater.send(()).await?;
-            },
+        let config = site.config();
+        let journal = site.journal();
+        log::debug!("Refreshing node graph for site {}/{}", config.name, config.branch);
+        // Fetched before taking any lock, update checks must not wait for the map
+        let meshinfo = match fetch_meshinfo(&config).await {
+            Ok(meshinfo) => meshinfo,
             Err(e) => {
                 log::error!(
                     "Failed to refresh node graph for site {}/{}: {}",
-                    site.config.name,
-                    site.config.branch,
+                    config.name,
+                    config.branch,
                     e
-                )
+                );
+                site.metrics.refresh_failure.fetch_add(1, Ordering::Relaxed);
+                continue;
             }
+        };
+
+        let start = Instant::now();
+        let mut new_graph = generate_graph(&config, &meshinfo, &site.persistent, &journal).await;
+        site.metrics.graph_build.observe(start.elapsed());
+        site.metrics.refresh_success.fetch_add(1, Ordering::Relaxed);
+        site.request_save();
+
+        let mut graph = site.graph.write().await;
+        // The rollout may have been paused or resumed while the graph was built
+        new_graph.paused = site.persistent.lock().await.rollout_state(config.dry_run).1.paused.is_some();
+        if graph.stale {
+            log::info!("Mesh data for site {}/{} is available again", config.name, config.branch);
         }
+        new_graph.journal_policy_changes(&graph, &journal);
+        *graph = new_graph;
+        drop(graph);
+        updater.send(()).await?;
     }
 }
 
+async fn save(persistent: &Mutex<PersistentState>, storage: &dyn storage::Storage, stats: &storage::SaveStats) {
+    let _writing = stats.writing.lock().await;
+    // Update checks must not wait for the disk, so only the copy is taken under the lock
+    let snapshot = persistent.lock().await.take_for_save(storage.writes_only_dirty());
+    let start = Instant::now();
+    let result = storage.save(&snapshot).await;
+    stats.record(start.elapsed(), result.is_ok());
+    if let Err(e) = result {
+        persistent.lock().await.save_failed(snapshot);
+        log::error!("Failed to write persistent state: {}", e);
+    }
+}
+
+/// Runs until the site is retired and holds the state lock until then, so the state of a removed
+/// site is not written after another site has taken over the state file
 async fn persitent_saver(
+    _lock: StateLock,
     site: Arc<Mutex<PersistentState>>,
-    file: PathBuf,
+    storage: Arc<dyn storage::Storage>,
+    stats: Arc<storage::SaveStats>,
+    interval: time::Duration,
     mut rx: mpsc::Receiver<()>,
+    stop: Arc<Notify>
 ) -> Result<(), failure::Error> {
-    while let Some(()) = rx.next().await {
-        log::debug!("Writing persistent state to {:?}", file);
-        fs::write(&file, &serde_json::to_string_pretty(&*site.lock().await)?).await?;
+    let mut stopping = false;
+    while !stopping {
+        tokio::select! {
+            request = rx.next() => if request.is_none() { break },
+            _ = stop.notified() => stopping = true
+        }
+        if !stopping {
+            // Changes arriving in the meantime are written with this save
+            tokio::select! {
+                _ = time::delay_for(interval) => {},
+                _ = stop.notified() => stopping = true
+            }
+        }
+        while rx.try_recv().is_ok() {}
+
+        log::debug!("Writing persistent state");
+        save(&site, &*storage, &stats).await;
+    }
+    Ok(())
+}
+
+/// Loads the state of a site and starts its background tasks
+async fn start_site(
+    site: SiteConfig,
+    journal: &Journal,
+    state_tx: &mpsc::Sender<()>
+) -> Result<Arc<SiteState>, failure::Error> {
+    log::info!("Preparing site {}/{}...", site.name, site.branch);
+
+    let lock = StateLock::acquire(&site.state_file)?;
+    let storage = storage::open(&site)?;
+    let pstate = storage.load().await?;
+
+    log::trace!("Loaded State: {:#?}", pstate);
+
+    let persistent = Arc::new(Mutex::new(pstate));
+
+    let (pers_tx, pers_rx) = mpsc::channel(1);
+    let save_stats = Arc::new(storage::SaveStats::default());
+
+    let site_journal = journal.for_site(&site.name, &site.branch, site.dry_run);
+    let graph = initial_graph(&site, &persistent, &site_journal).await?;
+
+    let stop_saver = Arc::new(Notify::new());
+    let saver = task::spawn(persitent_saver(
+        lock,
+        persistent.clone(),
+        storage.clone(),
+        save_stats.clone(),
+        time::Duration::from_secs(site.save_interval),
+        pers_rx,
+        stop_saver.clone()
+    ));
+
+    let state = Arc::new(SiteState {
+        graph: RwLock::new(graph),
+        persistent,
+        persistent_saver: pers_tx,
+        storage,
+        save_stats,
+        check_ins: Mutex::new(HashMap::new()),
+        metrics: metrics::SiteMetrics::default(),
+        refresh: Notify::new(),
+        retired: AtomicBool::new(false),
+        stop_saver,
+        saver: Mutex::new(Some(saver)),
+        settings: std::sync::RwLock::new((Arc::new(site), site_journal))
+    });
+    state.request_save();
+
+    let config = state.config();
+    log::info!("Spawning site {}/{} background task", config.name, config.branch);
+    task::spawn(configurator_task(state.clone(), state_tx.clone()));
+    Ok(state)
+}
+
+/// Records an action taken from the command line in the journal
+async fn journal_admin_action(config: &config::Config, site: &SiteConfig, reason: String) -> Result<(), failure::Error> {
+    let (journal, writer) = Journal::start(config.journal.as_ref());
+    journal.for_site(&site.name, &site.branch, site.dry_run)
+        .record(Decision::Admin, None, None, reason);
+    drop(journal);
+    if let Some(writer) = writer {
+        writer.await?;
+    }
+    Ok(())
+}
+
+async fn resume(config: &config::Config, site_name: &str, branch: &str) -> Result<(), failure::Error> {
+    let site = config.site(site_name, branch)?;
+
+    let _lock = StateLock::acquire(&site.state_file)?;
+    let storage = storage::open(site)?;
+    let mut pstate = storage.load().await?;
+    if let Some(pause) = pstate.rollout_state_mut(site.dry_run).1.paused.take() {
+        log::info!(
+            "Resuming rollout for site {}/{}, which was paused since {}: {}",
+            site_name,
+            branch,
+            pause.since,
+            pause.reason
+        );
+        // Start with a fresh window, otherwise the old failures would pause it right away
+        pstate.rollout_state_mut(site.dry_run).1.outcomes.clear();
+        storage.save(&pstate).await?;
+        journal_admin_action(config, site, format!("rollout resumed from command line, was paused: {}", pause.reason)).await?;
+    } else {
+        log::info!("Rollout for site {}/{} is not paused", site_name, branch);
+    }
+    Ok(())
+}
+
+/// Builds the graph from the current mesh data without changing the stored state, so this can run
+/// alongside the service. Neither the mesh data cache nor the state archive is written, and
+/// nothing is pruned
+async fn offline_graph(site: &SiteConfig) -> Result<(graph::Graph, PersistentState), failure::Error> {
+    let mut persistent = storage::open(site)?.load().await?;
+    let (journal, _) = Journal::start(None);
+    let graph = match download_meshinfo(site).await {
+        Ok((meshinfo, _)) => graph::Graph::build(&meshinfo, site, &mut persistent, &journal),
+        Err(e) => {
+            let meshinfo = fall_back_to_cache(site, e).await?;
+            let mut graph = graph::Graph::build(&meshinfo, site, &mut persistent.clone(), &journal);
+            graph.stale = true;
+            graph
+        }
+    };
+    Ok((graph, persistent))
+}
+
+async fn explain(site: &SiteConfig, node: &str) -> Result<(), failure::Error> {
+    let (graph, pstate) = offline_graph(site).await?;
+    let paused = pstate.rollout_state(site.dry_run).1.paused.as_ref();
+    match explain::explain(&graph, site, paused, node) {
+        Some(explanation) => {
+            println!("{}", explanation);
+            Ok(())
+        },
+        None => Err(failure::format_err!("Node {} is not known in site {}/{}", node, site.name, site.branch))
+    }
+}
+
+async fn export_graph(site: &SiteConfig, format: topology::Format, output: Option<&Path>) -> Result<(), failure::Error> {
+    let (graph, _) = offline_graph(site).await?;
+    let data = topology::render(&graph, site, format);
+    if let Some(output) = output {
+        fs::write(output, data).await?;
+    } else {
+        print!("{}", data);
     }
     Ok(())
 }
@@ -117,54 +584,86 @@ async fn main() -> Result<(), failure::Error> {
 
     let conf_file = matches.value_of("config").unwrap();
 
-    let config: config::Config = toml::from_str(&fs::read_to_string(conf_file).await?)?;
-
-    let (mut state_tx, state_rx) = mpsc::channel(8);
-
-    let mut site_map = HashMap::new();
-    for site in config.sites {
-        log::info!("Preparing site {}/{}...", site.name, site.branch);
+    let config = config::Config::parse(&fs::read_to_string(conf_file).await?)?;
 
-        let pstate = if site.state_file.exists() {
-            serde_json::from_str(&fs::read_to_string(&site.state_file).await?)?
-        } else {
-            Default::default()
-        };
+    if let Some(matches) = matches.subcommand_matches("resume") {
+        return resume(
+            &config,
+            matches.value_of("site").unwrap(),
+            matches.value_of("branch").unwrap()
+        ).await;
+    }
 
-        log::trace!("Loaded State: {:#?}", pstate);
+    if let Some(matches) = matches.subcommand_matches("export") {
+        return transfer::export(
+            config.site(matches.value_of("site").unwrap(), matches.value_of("branch").unwrap())?,
+            matches.value_of("output").map(Path::new)
+        ).await;
+    }
 
-        let persistent = Arc::new(Mutex::new(pstate));
+    if let Some(matches) = matches.subcommand_matches("explain") {
+        return explain(
+            config.site(matches.value_of("site").unwrap(), matches.value_of("branch").unwrap())?,
+            matches.value_of("node").unwrap()
+        ).await;
+    }
 
-        let (mut pers_tx, pers_rx) = mpsc::channel(8);
+    if let Some(matches) = matches.subcommand_matches("graph") {
+        return export_graph(
+            config.site(matches.value_of("site").unwrap(), matches.value_of("branch").unwrap())?,
+            matches.value_of("format").unwrap_or("dot").parse()?,
+            matches.value_of("output").map(Path::new)
+        ).await;
+    }
 
-        let state = Arc::new(SiteState {
-            graph: RwLock::new(generate_graph(&site, &mut *persistent.lock().await).await?),
-            persistent: persistent.clone(),
-            persistent_saver: pers_tx.clone(),
-            config: site.clone()
-        });
-        pers_tx.send(()).await?;
+    if let Some(matches) = matches.subcommand_matches("import") {
+        let site = config.site(matches.value_of("site").unwrap(), matches.value_of("branch").unwrap())?;
+        let input = Path::new(matches.value_of("input").unwrap());
+        transfer::import(site, input).await?;
+        return journal_admin_action(&config, site, format!("state imported from {:?}", input)).await;
+    }
 
-        site_map.insert((site.name.clone(), site.branch.clone()), state.clone());
+    let (mut state_tx, state_rx) = mpsc::channel(8);
 
-        task::spawn(persitent_saver(persistent.clone(), site.state_file.clone(), pers_rx));
+    let (journal, journal_writer) = Journal::start(config.journal.as_ref());
 
-        log::info!("Spawning site {}/{} background task", site.name, site.branch);
-        task::spawn(configurator_task(state, state_tx.clone()));
+    let mut site_map = HashMap::new();
+    for site in config.sites {
+        let state = start_site(site, &journal, &state_tx).await?;
+        let key = (state.config().name.clone(), state.config().branch.clone());
+        site_map.insert(key, state);
     }
 
     state_tx.send(()).await?;
 
     let state = Arc::new(MainState {
-        graphs: site_map,
-        listen_addr: config.listen.clone()
+        graphs: std::sync::RwLock::new(site_map),
+        listen_addr: config.listen,
+        admin_tokens: std::sync::RwLock::new(config.admin_tokens),
+        trusted_proxies: std::sync::RwLock::new(config.trusted_proxies),
+        config_file: PathBuf::from(conf_file),
+        journal,
+        state_tx,
+        reloading: Mutex::new(())
     });
 
     task::spawn(push_state_to_systemd_task(state.clone(), state_rx));
+    task::spawn(reload::signal_task(state.clone()));
 
     sd_notify::notify(false, &[NotifyState::Ready])?;
 
-    web::main(state).await?;
+    let result = web::main(state.clone()).await;
+
+    log::info!("Shutting down, writing persistent state");
+    for site in state.sites() {
+        site.save_now().await;
+    }
+    state.journal.close();
+    if let Some(writer) = journal_writer {
+        writer.await?;
+    }
+
+    result?;
 
     Ok(())
 }
@@ -172,7 +671,8 @@ async fn main() -> Result<(), failure::Error> {
 async fn push_state_to_systemd_task(state: Arc<MainState>, mut recv: mpsc::Receiver<()>) -> Result<(), failure::Error> {
     while let Some(()) = recv.next().await {
         let mut res = vec![];
-        for ((site_name, branch), site) in &state.graphs {
+        for site in state.sites() {
+            let config = site.config();
             let graph = site.graph.read().await;
             let migrated = graph.update_policy
                 .values()
@@ -188,9 +688,11 @@ async fn push_state_to_systemd_task(state: Arc<MainState>, mut recv: mpsc::Recei
                 .count();
             let total = graph.nodes.len();
             res.push(format!(
-                "{}/{}: {}/{}/{}/{}",
-                site_name, branch,
-                migrated, cleared, pending, total
+                "{}/{}: {}/{}/{}/{}{}{}",
+                config.name, config.branch,
+                migrated, cleared, pending, total,
+                if graph.paused { " (paused)" } else { "" },
+                if graph.stale { " (stale)" } else { "" }
             ))
         }
         let status = res.join(", ") + " migrated/cleared/blocked/total";
@@ -1,14 +1,12 @@
 use serde::{Serialize, Deserialize};
 use std::net::IpAddr;
-use std::fmt;
 use crate::mac::MacAddr;
 use crate::node_id::NodeID;
 
 #[derive(Deserialize, Debug)]
 pub struct MeshInfo {
     pub timestamp: chrono::DateTime<chrono::offset::Utc>,
-    pub nodes: Vec<Node>,
-    pub links: Vec<Link>
+    pub nodes: Vec<Node>
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -43,7 +41,7 @@ pub struct Node {
     pub model: Option<String>
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Location {
     pub longitude: f64,
     pub latitude: f64
@@ -60,25 +58,3 @@ pub struct Autoupdater {
     pub enabled: bool,
     pub branch: Option<String>
 }
-
-#[derive(Deserialize, Debug, Clone)]
-pub struct Link {
-    #[serde(rename = "type")]
-    pub ty: LinkType,
-    pub source: NodeID,
-    pub target: NodeID,
-    pub source_tq: f32,
-    pub target_tq: f32,
-    pub source_addr: MacAddr,
-    pub target_addr: MacAddr,
-}
-
-#[derive(Deserialize, Debug, Clone)]
-pub enum LinkType {
-    #[serde(rename = "wifi")]
-    Wireless,
-    #[serde(rename = "vpn")]
-    VPN,
-    #[serde(rename = "other")]
-    Other
-}
\ No newline at end of file
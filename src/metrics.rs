@@ -0,0 +1,230 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use crate::MainState;
+use crate::graph::UpdatePolicy;
+
+/// Upper bounds in seconds of the buckets used for duration histograms
+const DURATION_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+const POLICIES: &[(UpdatePolicy, &str)] = &[
+    (UpdatePolicy::Pending, "pending"),
+    (UpdatePolicy::Ready, "ready"),
+    (UpdatePolicy::Finished, "finished"),
+    (UpdatePolicy::Broken, "broken")
+];
+
+pub struct Histogram {
+    /// Observations per bucket, not cumulative. Observations above the last bound are only
+    /// counted in `count`
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: DURATION_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0)
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(idx) = DURATION_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in DURATION_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+/// How an update check has been answered
+#[derive(Clone, Copy)]
+pub enum CheckOutcome {
+    Update,
+    NoUpdate,
+    /// The node would have been sent the update, but the site is in dry-run mode
+    DryRunUpdate
+}
+
+/// Counters of a site, which are not derived from the graph
+#[derive(Default)]
+pub struct SiteMetrics {
+    checks_update: AtomicU64,
+    checks_no_update: AtomicU64,
+    checks_dry_run_update: AtomicU64,
+    pub refresh_success: AtomicU64,
+    pub refresh_failure: AtomicU64,
+    pub graph_build: Histogram
+}
+
+impl SiteMetrics {
+    pub fn record_check(&self, outcome: CheckOutcome) {
+        match outcome {
+            CheckOutcome::Update => &self.checks_update,
+            CheckOutcome::NoUpdate => &self.checks_no_update,
+            CheckOutcome::DryRunUpdate => &self.checks_dry_run_update
+        }.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, ty: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, ty);
+}
+
+/// Renders all metrics in the Prometheus text format
+pub async fn render(state: &MainState) -> String {
+    let sites = state.sites();
+
+    struct GraphValues {
+        labels: String,
+        policies: Vec<usize>,
+        max_depth: u8,
+        meshinfo_age: f64,
+        stale: bool,
+        paused: bool
+    }
+    let now = chrono::Utc::now();
+    let mut values = vec![];
+    for site in &sites {
+        let config = site.config();
+        let graph = site.graph.read().await;
+        values.push(GraphValues {
+            labels: format!("site=\"{}\",branch=\"{}\"", escape(&config.name), escape(&config.branch)),
+            policies: POLICIES.iter()
+                .map(|(policy, _)| graph.update_policy.values().filter(|p| *p == policy).count())
+                .collect(),
+            max_depth: graph.max_depth,
+            meshinfo_age: (now - graph.meshinfo_timestamp).num_milliseconds() as f64 / 1e3,
+            stale: graph.stale,
+            paused: graph.paused
+        });
+    }
+
+    let mut out = String::new();
+
+    header(&mut out, "gluon_update_manager_nodes", "gauge", "Nodes in the graph by update policy");
+    for v in &values {
+        for ((_, name), count) in POLICIES.iter().zip(&v.policies) {
+            let _ = writeln!(out, "gluon_update_manager_nodes{{{},policy=\"{}\"}} {}", v.labels, name, count);
+        }
+    }
+
+    header(&mut out, "gluon_update_manager_max_depth", "gauge", "Depth of the deepest node in the mesh tree");
+    for v in &values {
+        let _ = writeln!(out, "gluon_update_manager_max_depth{{{}}} {}", v.labels, v.max_depth);
+    }
+
+    header(&mut out, "gluon_update_manager_meshinfo_age_seconds", "gauge", "Age of the mesh data the graph is built from");
+    for v in &values {
+        let _ = writeln!(out, "gluon_update_manager_meshinfo_age_seconds{{{}}} {}", v.labels, v.meshinfo_age);
+    }
+
+    header(&mut out, "gluon_update_manager_stale", "gauge", "Whether the graph is built from cached mesh data");
+    for v in &values {
+        let _ = writeln!(out, "gluon_update_manager_stale{{{}}} {}", v.labels, v.stale as u8);
+    }
+
+    header(&mut out, "gluon_update_manager_paused", "gauge", "Whether the rollout is paused");
+    for v in &values {
+        let _ = writeln!(out, "gluon_update_manager_paused{{{}}} {}", v.labels, v.paused as u8);
+    }
+
+    header(&mut out, "gluon_update_manager_update_checks_total", "counter", "Answered update checks by outcome");
+    for (site, v) in sites.iter().zip(&values) {
+        for (outcome, counter) in &[
+            ("update", &site.metrics.checks_update),
+            ("no_update", &site.metrics.checks_no_update),
+            ("dry_run_update", &site.metrics.checks_dry_run_update)
+        ] {
+            let _ = writeln!(
+                out,
+                "gluon_update_manager_update_checks_total{{{},outcome=\"{}\"}} {}",
+                v.labels,
+                outcome,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    header(&mut out, "gluon_update_manager_refreshes_total", "counter", "Graph refreshes by result");
+    for (site, v) in sites.iter().zip(&values) {
+        for (result, counter) in &[
+            ("success", &site.metrics.refresh_success),
+            ("failure", &site.metrics.refresh_failure)
+        ] {
+            let _ = writeln!(
+                out,
+                "gluon_update_manager_refreshes_total{{{},result=\"{}\"}} {}",
+                v.labels,
+                result,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    header(&mut out, "gluon_update_manager_graph_build_duration_seconds", "histogram", "Time taken to build the graph");
+    for (site, v) in sites.iter().zip(&values) {
+        site.metrics.graph_build.write(&mut out, "gluon_update_manager_graph_build_duration_seconds", &v.labels);
+    }
+
+    header(&mut out, "gluon_update_manager_state_save_requests_total", "counter", "Requested writes of the persistent state");
+    for (site, v) in sites.iter().zip(&values) {
+        let _ = writeln!(out, "gluon_update_manager_state_save_requests_total{{{}}} {}", v.labels, site.save_stats.requested.load(Ordering::Relaxed));
+    }
+
+    header(&mut out, "gluon_update_manager_state_saves_total", "counter", "Writes of the persistent state");
+    for (site, v) in sites.iter().zip(&values) {
+        let _ = writeln!(out, "gluon_update_manager_state_saves_total{{{}}} {}", v.labels, site.save_stats.saves.load(Ordering::Relaxed));
+    }
+
+    header(&mut out, "gluon_update_manager_state_save_failures_total", "counter", "Failed writes of the persistent state");
+    for (site, v) in sites.iter().zip(&values) {
+        let _ = writeln!(out, "gluon_update_manager_state_save_failures_total{{{}}} {}", v.labels, site.save_stats.failures.load(Ordering::Relaxed));
+    }
+
+    header(&mut out, "gluon_update_manager_state_save_duration_seconds", "histogram", "Time taken to write the persistent state");
+    for (site, v) in sites.iter().zip(&values) {
+        site.save_stats.durations.write(&mut out, "gluon_update_manager_state_save_duration_seconds", &v.labels);
+    }
+
+    out
+}
+
+#[test]
+fn test_histogram_cumulative() {
+    let histogram = Histogram::default();
+    histogram.observe(Duration::from_millis(3));
+    histogram.observe(Duration::from_millis(200));
+    histogram.observe(Duration::from_secs(60));
+    let mut out = String::new();
+    histogram.write(&mut out, "h", "site=\"a\"");
+    assert!(out.contains("h_bucket{site=\"a\",le=\"0.001\"} 0\n"));
+    assert!(out.contains("h_bucket{site=\"a\",le=\"0.005\"} 1\n"));
+    assert!(out.contains("h_bucket{site=\"a\",le=\"0.25\"} 2\n"));
+    assert!(out.contains("h_bucket{site=\"a\",le=\"10\"} 2\n"));
+    assert!(out.contains("h_bucket{site=\"a\",le=\"+Inf\"} 3\n"));
+    assert!(out.contains("h_count{site=\"a\"} 3\n"));
+}
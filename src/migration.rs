@@ -0,0 +1,125 @@
+use std::path::Path;
+use serde_json::{json, Value};
+use crate::persistence::PersistentState;
+
+/// Version of the state layout written by this build. Bump it and add a step to `MIGRATIONS`
+/// whenever the layout changes in a way `#[serde(default)]` can't cover.
+pub const STATE_VERSION: u32 = 1;
+
+type Migration = fn(&mut Value) -> Result<(), failure::Error>;
+
+/// `MIGRATIONS[n]` upgrades a state of version `n` to version `n + 1`
+const MIGRATIONS: &[Migration] = &[
+    migrate_v0_to_v1
+];
+
+/// Version of a stored state. States written before versioning was introduced are version 0
+pub fn version_of(state: &Value) -> u32 {
+    state.get("version")
+        .and_then(Value::as_u64)
+        .unwrap_or(0) as u32
+}
+
+/// Fails if the state has been written by a newer version of the update manager
+pub fn ensure_supported(state: &Value, source: &Path) -> Result<(), failure::Error> {
+    check_version(version_of(state), source)
+}
+
+pub fn check_version(version: u32, source: &Path) -> Result<(), failure::Error> {
+    if version > STATE_VERSION {
+        Err(failure::format_err!(
+            "State {:?} has been written by a newer version of gluon-update-manager (state version {}, \
+            this version supports up to {}), refusing to start",
+            source,
+            version,
+            STATE_VERSION
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Upgrades a stored state to the current layout
+pub fn migrate(mut state: Value) -> Result<PersistentState, failure::Error> {
+    let version = version_of(&state);
+    if version > STATE_VERSION {
+        return Err(failure::format_err!("Unsupported state version {}", version));
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        log::info!("Migrating state from version {} to {}", from, from + 1);
+        migration(&mut state)?;
+    }
+
+    if let Some(object) = state.as_object_mut() {
+        object.insert("version".to_owned(), json!(STATE_VERSION));
+    }
+    Ok(serde_json::from_value(state)?)
+}
+
+/// Link history used to only keep the first uplink ever seen, and node state did not have an
+/// explicit rollout state
+fn migrate_v0_to_v1(state: &mut Value) -> Result<(), failure::Error> {
+    if let Some(history) = state.get_mut("link_history").and_then(Value::as_object_mut) {
+        for link in history.values_mut() {
+            if let (Some(uplink), Some(since)) = (link.get("uplink"), link.get("since")) {
+                *link = json!({
+                    "uplinks": [{
+                        "uplink": uplink,
+                        "first_seen": since,
+                        "last_seen": since,
+                        "observations": 1
+                    }]
+                });
+            }
+        }
+    }
+
+    if let Some(nodes) = state.get_mut("node_state").and_then(Value::as_object_mut) {
+        let now = chrono::Utc::now();
+        for node in nodes.values_mut().filter_map(Value::as_object_mut) {
+            if node.contains_key("state") {
+                continue;
+            }
+            let received = node.get("update_received").cloned().unwrap_or(Value::Null);
+            let attempts = node.get("update_attempts").and_then(Value::as_u64).unwrap_or(0);
+            let (rollout_state, at) = if !received.is_null() {
+                ("served", received)
+            } else if attempts > 0 {
+                ("failed", json!(now))
+            } else {
+                continue;
+            };
+            node.insert("state".to_owned(), json!(rollout_state));
+            node.insert("transitions".to_owned(), json!([{ "state": rollout_state, "at": at }]));
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_migrate_v0() {
+    let state = migrate(json!({
+        "node_state": {
+            "001122334455": { "update_received": "2020-09-01T12:00:00Z", "update_attempts": 0 },
+            "66778899aabb": { "update_received": null, "update_attempts": 2 }
+        },
+        "link_history": {
+            "001122334455": { "uplink": "66778899aabb", "since": "2020-09-01T12:00:00Z" }
+        }
+    })).unwrap();
+
+    let served = "001122334455".parse().unwrap();
+    let failed = "66778899aabb".parse().unwrap();
+    assert_eq!(state.version, STATE_VERSION);
+    assert_eq!(state.node_state[&served].state, crate::persistence::RolloutState::Served);
+    assert_eq!(state.node_state[&failed].state, crate::persistence::RolloutState::Failed);
+    assert_eq!(state.link_history[&served].uplinks[0].uplink, failed);
+}
+
+#[test]
+fn test_refuse_newer() {
+    let state = json!({ "version": STATE_VERSION + 1 });
+    assert!(ensure_supported(&state, Path::new("state.json")).is_err());
+}
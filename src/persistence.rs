@@ -1,38 +1,849 @@
-use std::collections::HashMap;
+use std::collections::{hash_map::Entry, HashMap, HashSet};
 use crate::node_id::NodeID;
+use crate::config::UplinkSelection;
+use crate::meshinfo::Location;
+use crate::graph::UpdatePolicy;
 use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PersistentState {
+    /// Layout version of the state, see `migration`
+    pub version: u32,
     #[serde(default)]
     pub node_state: HashMap<NodeID, NodeState>,
     #[serde(default)]
-    pub link_history: HashMap<NodeID, LinkInfo>
+    pub link_history: HashMap<NodeID, LinkHistory>,
+    /// When each node has last been present in the mesh data
+    #[serde(default)]
+    pub last_seen: HashMap<NodeID, chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    pub rollout: RolloutHealth,
+    #[serde(default)]
+    pub dry_run: DryRunState,
+    /// Update policies set by an administrator. They apply to the real rollout and the dry run
+    /// alike and are kept until cleared, even for nodes which are pruned
+    #[serde(default)]
+    pub overrides: HashMap<NodeID, Override>,
+    /// Nodes whose entries changed since the state has last been taken for a save. Code changing
+    /// the per-node maps directly has to call `mark_dirty`
+    #[serde(skip)]
+    dirty: HashSet<NodeID>
+}
+
+/// An update policy forced by an administrator, regardless of what the graph says
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Override {
+    pub policy: UpdatePolicy,
+    pub by: String,
+    pub at: chrono::DateTime<chrono::Utc>,
+    pub reason: Option<String>
+}
+
+/// Rollout state recorded while the site is in dry-run mode, kept apart so it never affects the
+/// real rollout
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct DryRunState {
+    #[serde(default)]
+    pub node_state: HashMap<NodeID, NodeState>,
+    #[serde(default)]
+    pub rollout: RolloutHealth
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct LinkInfo {
+impl Default for PersistentState {
+    fn default() -> Self {
+        PersistentState {
+            version: crate::migration::STATE_VERSION,
+            node_state: HashMap::new(),
+            link_history: HashMap::new(),
+            last_seen: HashMap::new(),
+            rollout: RolloutHealth::default(),
+            dry_run: DryRunState::default(),
+            overrides: HashMap::new(),
+            dirty: HashSet::new()
+        }
+    }
+}
+
+/// Outcomes of recent update attempts, used to stop the rollout when too many of them fail
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct RolloutHealth {
+    pub outcomes: Vec<UpdateOutcome>,
+    /// If set, the rollout has been stopped and no node will be sent the update until it is
+    /// resumed manually
+    pub paused: Option<Pause>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UpdateOutcome {
+    pub at: chrono::DateTime<chrono::Utc>,
+    pub node: NodeID,
+    pub success: bool
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Pause {
+    pub since: chrono::DateTime<chrono::Utc>,
+    pub reason: String
+}
+
+impl RolloutHealth {
+    pub fn merge(&mut self, other: RolloutHealth) {
+        for outcome in other.outcomes {
+            let known = self.outcomes.iter()
+                .any(|o| o.at == outcome.at && o.node == outcome.node && o.success == outcome.success);
+            if !known {
+                self.outcomes.push(outcome);
+            }
+        }
+        self.outcomes.sort_by_key(|o| o.at);
+        // Either side being paused means something went wrong, keep it paused
+        if self.paused.is_none() {
+            self.paused = other.paused;
+        }
+    }
+
+    /// Applies the outcomes added from `before` to `after`. If this has not changed in the meantime,
+    /// `after` is taken as it is. A pause from `after` is kept either way, it has been journaled and
+    /// served already
+    fn apply_changes(&mut self, before: &RolloutHealth, after: RolloutHealth) {
+        if self == before {
+            *self = after;
+            return;
+        }
+        for outcome in after.outcomes {
+            if !before.outcomes.contains(&outcome) {
+                self.outcomes.push(outcome);
+            }
+        }
+        if before.paused.is_none() && self.paused.is_none() {
+            self.paused = after.paused;
+        }
+    }
+
+    /// Records the outcome of an update attempt. Outcomes older than `window` are dropped, so the
+    /// state does not grow when the circuit breaker is disabled and `evaluate` never runs
+    pub fn record(&mut self, node: NodeID, success: bool, at: chrono::DateTime<chrono::Utc>, window: chrono::Duration) {
+        self.outcomes.retain(|o| at - o.at <= window);
+        self.outcomes.push(UpdateOutcome { at, node, success });
+    }
+
+    /// Drops outcomes older than `window` and pauses the rollout if the share of failures within
+    /// the window reaches `threshold`. Returns true if the rollout has just been paused.
+    pub fn evaluate(
+        &mut self,
+        window: chrono::Duration,
+        threshold: f64,
+        min_samples: usize,
+        now: chrono::DateTime<chrono::Utc>
+    ) -> bool {
+        self.outcomes.retain(|o| now - o.at <= window);
+
+        if self.paused.is_some() || self.outcomes.is_empty() || self.outcomes.len() < min_samples {
+            return false;
+        }
+
+        let failures = self.outcomes.iter().filter(|o| !o.success).count();
+        let rate = failures as f64 / self.outcomes.len() as f64;
+        if rate >= threshold {
+            self.paused = Some(Pause {
+                since: now,
+                reason: format!(
+                    "{} of {} update attempts failed within {} hours",
+                    failures,
+                    self.outcomes.len(),
+                    window.num_hours()
+                )
+            });
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Everything known about a node which is removed from the persistent state
+#[derive(Serialize, Debug)]
+pub struct PrunedNode {
+    pub node_id: NodeID,
+    pub pruned_at: chrono::DateTime<chrono::Utc>,
+    pub last_seen: Option<chrono::DateTime<chrono::Utc>>,
+    pub node_state: Option<NodeState>,
+    pub dry_run_state: Option<NodeState>,
+    pub link_history: Option<LinkHistory>
+}
+
+/// All uplinks a node has been observed with
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct LinkHistory {
+    pub uplinks: Vec<LinkRecord>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LinkRecord {
     pub uplink: NodeID,
-    pub since: chrono::DateTime<chrono::Utc>
+    pub first_seen: chrono::DateTime<chrono::Utc>,
+    pub last_seen: chrono::DateTime<chrono::Utc>,
+    pub observations: u64
 }
 
-impl PersistentState {
-    pub fn update_node(&mut self, name: &NodeID) {
-        if let Some(node) = self.node_state.get_mut(name) {
-            if node.update_received.is_none() {
-                node.update_received = Some(chrono::offset::Utc::now());
+impl LinkHistory {
+    /// Merges another history of the same node into this one
+    pub fn merge(&mut self, other: LinkHistory) {
+        for record in other.uplinks {
+            if let Some(existing) = self.uplinks.iter_mut().find(|r| r.uplink == record.uplink) {
+                existing.first_seen = existing.first_seen.min(record.first_seen);
+                existing.last_seen = existing.last_seen.max(record.last_seen);
+                existing.observations = existing.observations.max(record.observations);
+            } else {
+                self.uplinks.push(record);
             }
+        }
+    }
+
+    /// Records that the node has been seen with the given uplink
+    pub fn observe(&mut self, uplink: NodeID, now: chrono::DateTime<chrono::Utc>) {
+        if let Some(record) = self.uplinks.iter_mut().find(|r| r.uplink == uplink) {
+            record.last_seen = now;
+            record.observations += 1;
         } else {
-            self.node_state.insert(name.clone(), NodeState {
-                update_received: Some(chrono::offset::Utc::now()),
-                .. NodeState::default()
+            self.uplinks.push(LinkRecord {
+                uplink,
+                first_seen: now,
+                last_seen: now,
+                observations: 1
             });
         }
     }
+
+    /// The uplink the node has been seen with most recently
+    pub fn most_recent(&self) -> Option<&LinkRecord> {
+        self.uplinks.iter().max_by_key(|r| r.last_seen)
+    }
+
+    /// The uplink the node has been seen with most often. Ties are broken by recency
+    pub fn most_frequent(&self) -> Option<&LinkRecord> {
+        self.uplinks.iter().max_by_key(|r| (r.observations, r.last_seen))
+    }
+
+    pub fn preferred(&self, selection: UplinkSelection) -> Option<&LinkRecord> {
+        match selection {
+            UplinkSelection::Recent => self.most_recent(),
+            UplinkSelection::Frequent => self.most_frequent()
+        }
+    }
+}
+
+impl PersistentState {
+    /// Merges state from another source (e.g. another installation) into this one
+    pub fn merge(&mut self, other: PersistentState) {
+        self.dirty.extend(other.node_state.keys()
+            .chain(other.dry_run.node_state.keys())
+            .chain(other.link_history.keys())
+            .chain(other.last_seen.keys())
+            .chain(other.overrides.keys()));
+        merge_node_states(&mut self.node_state, other.node_state);
+        merge_node_states(&mut self.dry_run.node_state, other.dry_run.node_state);
+        for (node, history) in other.link_history {
+            self.link_history.entry(node).or_default().merge(history);
+        }
+        for (node, seen) in other.last_seen {
+            let entry = self.last_seen.entry(node).or_insert(seen);
+            *entry = (*entry).max(seen);
+        }
+        self.rollout.merge(other.rollout);
+        self.dry_run.rollout.merge(other.dry_run.rollout);
+        for (node, other_override) in other.overrides {
+            match self.overrides.get(&node) {
+                Some(existing) if existing.at >= other_override.at => {},
+                _ => {
+                    self.overrides.insert(node, other_override);
+                }
+            }
+        }
+    }
+
+    /// Applies what changed from `before` to `after` to this state. Entries which have been changed
+    /// here in the meantime, e.g. by an update check, are kept, the next refresh looks at them again
+    pub fn apply_changes(&mut self, before: &PersistentState, after: PersistentState) {
+        let dirty = &mut self.dirty;
+        apply_entry_changes(&mut self.node_state, &before.node_state, after.node_state, dirty);
+        apply_entry_changes(&mut self.dry_run.node_state, &before.dry_run.node_state, after.dry_run.node_state, dirty);
+        apply_entry_changes(&mut self.link_history, &before.link_history, after.link_history, dirty);
+        apply_entry_changes(&mut self.last_seen, &before.last_seen, after.last_seen, dirty);
+        self.rollout.apply_changes(&before.rollout, after.rollout);
+        self.dry_run.rollout.apply_changes(&before.dry_run.rollout, after.dry_run.rollout);
+    }
+
+    /// Node states and rollout health of either the real rollout or the dry run
+    pub fn rollout_state(&self, dry_run: bool) -> (&HashMap<NodeID, NodeState>, &RolloutHealth) {
+        if dry_run {
+            (&self.dry_run.node_state, &self.dry_run.rollout)
+        } else {
+            (&self.node_state, &self.rollout)
+        }
+    }
+
+    pub fn rollout_state_mut(&mut self, dry_run: bool) -> (&mut HashMap<NodeID, NodeState>, &mut RolloutHealth) {
+        if dry_run {
+            (&mut self.dry_run.node_state, &mut self.dry_run.rollout)
+        } else {
+            (&mut self.node_state, &mut self.rollout)
+        }
+    }
+
+    /// Records that the node has been sent the update. In a dry run, this only records that it
+    /// would have been
+    pub fn update_node(&mut self, name: &NodeID, dry_run: bool) {
+        let now = chrono::offset::Utc::now();
+        let node = self.rollout_state_mut(dry_run).0.entry(*name).or_default();
+        if node.update_received.is_none() {
+            node.update_received = Some(now);
+            node.transition(RolloutState::Served, now);
+            self.dirty.insert(*name);
+        }
+    }
+
+    pub fn mark_seen(&mut self, node: NodeID, now: chrono::DateTime<chrono::Utc>) {
+        self.last_seen.insert(node, now);
+        self.dirty.insert(node);
+    }
+
+    /// Records that an entry of the node has been changed, so it is written with the next save
+    pub fn mark_dirty(&mut self, node: NodeID) {
+        self.dirty.insert(node);
+    }
+
+    /// Nodes whose entries have changed since the state has last been taken for a save
+    pub fn dirty(&self) -> &HashSet<NodeID> {
+        &self.dirty
+    }
+
+    /// A copy of the state to be saved. With `only_dirty`, the per-node maps of the copy only hold
+    /// the entries of changed nodes. Changes are tracked anew from now on, if the save fails the
+    /// copy has to be handed to `save_failed`
+    pub fn take_for_save(&mut self, only_dirty: bool) -> PersistentState {
+        let snapshot = if only_dirty {
+            PersistentState {
+                version: self.version,
+                node_state: dirty_entries(&self.node_state, &self.dirty),
+                link_history: dirty_entries(&self.link_history, &self.dirty),
+                last_seen: dirty_entries(&self.last_seen, &self.dirty),
+                rollout: self.rollout.clone(),
+                dry_run: DryRunState {
+                    node_state: dirty_entries(&self.dry_run.node_state, &self.dirty),
+                    rollout: self.dry_run.rollout.clone()
+                },
+                overrides: dirty_entries(&self.overrides, &self.dirty),
+                dirty: self.dirty.clone()
+            }
+        } else {
+            self.clone()
+        };
+        self.dirty.clear();
+        snapshot
+    }
+
+    /// Marks the nodes which have not been written by a failed save as changed again
+    pub fn save_failed(&mut self, snapshot: PersistentState) {
+        self.dirty.extend(snapshot.dirty);
+    }
+
+    /// Gives nodes which have state, but no last seen record (because the state was written by an
+    /// older version) a record of `now`, so they get the full retention time. Done when loading
+    /// state, `stale_nodes` relies on it.
+    pub fn backfill_last_seen(&mut self, now: chrono::DateTime<chrono::Utc>) {
+        let known: Vec<NodeID> = self.node_state.keys()
+            .chain(self.dry_run.node_state.keys())
+            .chain(self.link_history.keys())
+            .copied()
+            .collect();
+        for node in known {
+            if let Entry::Vacant(entry) = self.last_seen.entry(node) {
+                entry.insert(now);
+                self.dirty.insert(node);
+            }
+        }
+    }
+
+    /// Nodes which have state stored, but have not been present in the mesh data since `cutoff`
+    pub fn stale_nodes(&self, cutoff: chrono::DateTime<chrono::Utc>) -> Vec<NodeID> {
+        self.last_seen
+            .iter()
+            .filter(|(_, seen)| **seen < cutoff)
+            .map(|(node, _)| *node)
+            .collect()
+    }
+
+    /// Removes the given nodes from the state, returning what was stored about them
+    pub fn prune(&mut self, nodes: &[NodeID]) -> Vec<PrunedNode> {
+        let now = chrono::Utc::now();
+        self.dirty.extend(nodes);
+        nodes.iter()
+            .map(|node| PrunedNode {
+                node_id: *node,
+                pruned_at: now,
+                last_seen: self.last_seen.remove(node),
+                node_state: self.node_state.remove(node),
+                dry_run_state: self.dry_run.node_state.remove(node),
+                link_history: self.link_history.remove(node)
+            })
+            .collect()
+    }
+
+    pub fn record_uplink(&mut self, node: NodeID, uplink: NodeID, now: chrono::DateTime<chrono::Utc>) {
+        self.dirty.insert(node);
+        self.link_history
+            .entry(node)
+            .or_default()
+            .observe(uplink, now);
+    }
+}
+
+fn merge_node_states(into: &mut HashMap<NodeID, NodeState>, from: HashMap<NodeID, NodeState>) {
+    for (node, state) in from {
+        match into.get_mut(&node) {
+            Some(existing) => existing.merge(state),
+            None => {
+                into.insert(node, state);
+            }
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+fn apply_entry_changes<T: PartialEq>(
+    into: &mut HashMap<NodeID, T>,
+    before: &HashMap<NodeID, T>,
+    after: HashMap<NodeID, T>,
+    dirty: &mut HashSet<NodeID>
+) {
+    for (node, old) in before {
+        if !after.contains_key(node) && into.get(node) == Some(old) {
+            into.remove(node);
+            dirty.insert(*node);
+        }
+    }
+    for (node, new) in after {
+        let old = before.get(&node);
+        if old != Some(&new) && into.get(&node) == old {
+            into.insert(node, new);
+            dirty.insert(node);
+        }
+    }
+}
+
+fn dirty_entries<T: Clone>(map: &HashMap<NodeID, T>, dirty: &HashSet<NodeID>) -> HashMap<NodeID, T> {
+    dirty.iter()
+        .filter_map(|id| map.get(id).map(|entry| (*id, entry.clone())))
+        .collect()
+}
+
+/// Path next to `file` with `suffix` appended to its file name
+pub(crate) fn sibling(file: &Path, suffix: &str) -> PathBuf {
+    let mut name = file.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Minimum age of the newest backup before it is rotated
+const BACKUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+fn backup_path(file: &Path, n: usize) -> PathBuf {
+    sibling(file, &format!(".{}", n))
+}
+
+/// Whether the newest backup is old enough to be replaced by the current version of the state
+async fn backup_due(file: &Path) -> Result<bool, failure::Error> {
+    match fs::metadata(backup_path(file, 1)).await {
+        Ok(metadata) => Ok(metadata.modified()?.elapsed().map_or(true, |age| age >= BACKUP_INTERVAL)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(e.into())
+    }
+}
+
+/// Writes the state to `file` without ever leaving a partially written file behind. The previous
+/// `backups` versions are kept as `file.1` (newest) to `file.N` (oldest), at most one per
+/// `BACKUP_INTERVAL`, so they reach back further than the last few saves.
+pub async fn save(file: &Path, state: &PersistentState, backups: usize) -> Result<(), failure::Error> {
+    let tmp = sibling(file, ".tmp");
+    {
+        let mut out = fs::File::create(&tmp).await?;
+        out.write_all(serde_json::to_string_pretty(state)?.as_bytes()).await?;
+        out.sync_all().await?;
+    }
+
+    if backups > 0 && file.exists() && backup_due(file).await? {
+        for n in (1..backups).rev() {
+            let from = backup_path(file, n);
+            if from.exists() {
+                fs::rename(&from, backup_path(file, n + 1)).await?;
+            }
+        }
+        // A hard link keeps the current version in place until the rename below replaces it
+        let newest = backup_path(file, 1);
+        if fs::hard_link(file, &newest).await.is_err() {
+            let copy = sibling(&newest, ".tmp");
+            fs::copy(file, &copy).await?;
+            fs::rename(&copy, &newest).await?;
+        }
+    }
+
+    fs::rename(&tmp, file).await?;
+
+    if let Some(dir) = file.parent() {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        fs::File::open(dir).await?.sync_all().await?;
+    }
+    Ok(())
+}
+
+/// Loads the state from `file`, falling back to the newest backup which can be read if the file
+/// itself is missing or damaged
+pub async fn load(file: &Path, backups: usize) -> Result<PersistentState, failure::Error> {
+    let candidates: Vec<PathBuf> = std::iter::once(file.to_owned())
+        .chain((1..=backups).map(|n| backup_path(file, n)))
+        .filter(|path| path.exists())
+        .collect();
+
+    if candidates.is_empty() {
+        return Ok(PersistentState::default());
+    }
+
+    let mut errors = vec![];
+    for path in candidates {
+        let value = fs::read_to_string(&path)
+            .await
+            .map_err(failure::Error::from)
+            .and_then(|data| Ok(serde_json::from_str::<serde_json::Value>(&data)?));
+        let parsed = match value {
+            Ok(value) => {
+                // Falling back to an older backup would lose whatever the newer version stored
+                crate::migration::ensure_supported(&value, &path)?;
+                crate::migration::migrate(value)
+            },
+            Err(e) => Err(e)
+        };
+        match parsed {
+            Ok(mut state) => {
+                state.backfill_last_seen(chrono::Utc::now());
+                if path != file {
+                    log::error!(
+                        "State file {:?} is missing or damaged ({}), RECOVERED STATE FROM BACKUP {:?}",
+                        file,
+                        errors.join("; "),
+                        path
+                    );
+                }
+                return Ok(state);
+            },
+            Err(e) => {
+                log::error!("Failed to load state from {:?}: {}", path, e);
+                errors.push(format!("{:?}: {}", path, e));
+            }
+        }
+    }
+
+    Err(failure::format_err!("No valid state file found: {}", errors.join("; ")))
+}
+
+/// Appends pruned nodes to the archive file, one JSON document per line
+pub async fn archive(file: &Path, pruned: &[PrunedNode]) -> Result<(), failure::Error> {
+    let mut data = String::new();
+    for node in pruned {
+        data += &serde_json::to_string(node)?;
+        data.push('\n');
+    }
+
+    let mut archive = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file)
+        .await?;
+    archive.write_all(data.as_bytes()).await?;
+    archive.flush().await?;
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct NodeState {
     pub update_received: Option<chrono::DateTime<chrono::offset::Utc>>,
-    pub update_attempts: u32
-}
\ No newline at end of file
+    pub update_attempts: u32,
+    #[serde(default)]
+    pub state: RolloutState,
+    /// Every state change of the node, oldest first
+    #[serde(default)]
+    pub transitions: Vec<StateTransition>,
+    /// When the uplink of the node, which is still offline after its update, has been seen updated
+    /// and online again
+    #[serde(default)]
+    pub uplink_back_since: Option<chrono::DateTime<chrono::offset::Utc>>,
+    /// Set if the node did not come back after its uplink was updated and might be bricked
+    #[serde(default)]
+    pub lost: Option<LostNode>
+}
+
+impl NodeState {
+    /// Merges the state of the same node from another source, keeping the newest timestamps and
+    /// the highest attempt count
+    pub fn merge(&mut self, other: NodeState) {
+        self.update_attempts = self.update_attempts.max(other.update_attempts);
+        self.uplink_back_since = self.uplink_back_since.max(other.uplink_back_since);
+        if other.state_since() > self.state_since() {
+            self.state = other.state;
+            self.transitions = other.transitions;
+            self.update_received = other.update_received.or(self.update_received);
+            self.lost = other.lost;
+        } else {
+            self.update_received = self.update_received.max(other.update_received);
+        }
+    }
+
+    pub fn transition(&mut self, state: RolloutState, at: chrono::DateTime<chrono::offset::Utc>) {
+        if self.state != state {
+            self.state = state;
+            self.transitions.push(StateTransition { state, at });
+        }
+    }
+
+    /// When the node has entered its current state
+    pub fn state_since(&self) -> Option<chrono::DateTime<chrono::offset::Utc>> {
+        self.transitions.last().map(|t| t.at)
+    }
+}
+
+/// Where a node is in the rollout
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RolloutState {
+    /// The node has not received the update yet
+    #[default]
+    Waiting,
+    /// The node has been redirected to the new firmware
+    Served,
+    /// The node went offline after being served the update, presumably to flash it
+    Flashing,
+    /// The node came back running the latest version
+    Confirmed,
+    /// The node stayed offline for longer than the update timeout and is assumed to be updated
+    Assumed,
+    /// The node came back with the old version
+    Failed,
+    /// The node did not come back after its uplink was updated and might be bricked
+    Lost
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StateTransition {
+    pub state: RolloutState,
+    pub at: chrono::DateTime<chrono::offset::Utc>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LostNode {
+    pub since: chrono::DateTime<chrono::offset::Utc>,
+    pub hostname: String,
+    pub owner: Option<String>,
+    pub location: Option<Location>,
+    pub last_seen: chrono::DateTime<chrono::offset::Utc>,
+    pub uplink: Option<NodeID>
+}
+
+#[test]
+fn test_uplink_selection() {
+    let a: NodeID = "001122334455".parse().unwrap();
+    let b: NodeID = "66778899aabb".parse().unwrap();
+    let start = chrono::Utc::now();
+    let mut history = LinkHistory::default();
+    for i in 0..3 {
+        history.observe(a, start + chrono::Duration::minutes(i));
+    }
+    history.observe(b, start + chrono::Duration::minutes(10));
+
+    assert_eq!(history.preferred(UplinkSelection::Recent).unwrap().uplink, b);
+    assert_eq!(history.preferred(UplinkSelection::Frequent).unwrap().uplink, a);
+}
+
+#[test]
+fn test_prune_stale_nodes() {
+    let gone: NodeID = "001122334455".parse().unwrap();
+    let active: NodeID = "66778899aabb".parse().unwrap();
+    let now = chrono::Utc::now();
+
+    let mut state = PersistentState::default();
+    state.update_node(&gone, false);
+    state.update_node(&active, false);
+    state.record_uplink(gone, active, now);
+    state.mark_seen(gone, now - chrono::Duration::days(100));
+    state.mark_seen(active, now);
+
+    let stale = state.stale_nodes(now - chrono::Duration::days(30));
+    assert_eq!(stale, vec![gone]);
+
+    // Nodes stored by an older version without a last seen record get the full retention time
+    let unrecorded: NodeID = "aabbccddeeff".parse().unwrap();
+    state.update_node(&unrecorded, false);
+    state.backfill_last_seen(now);
+    assert_eq!(state.last_seen[&unrecorded], now);
+    assert_eq!(state.stale_nodes(now - chrono::Duration::days(30)), vec![gone]);
+
+    let pruned = state.prune(&stale);
+    assert_eq!(pruned.len(), 1);
+    assert!(pruned[0].node_state.is_some());
+    assert!(pruned[0].link_history.is_some());
+    assert!(!state.node_state.contains_key(&gone));
+    assert!(!state.link_history.contains_key(&gone));
+    assert!(state.node_state.contains_key(&active));
+}
+
+#[test]
+fn test_circuit_breaker() {
+    let node: NodeID = "001122334455".parse().unwrap();
+    let now = chrono::Utc::now();
+    let window = chrono::Duration::hours(24);
+    let mut health = RolloutHealth::default();
+
+    // Outcomes outside of the window do not count and are dropped
+    for _ in 0..10 {
+        health.record(node, false, now - chrono::Duration::hours(48), window);
+    }
+    assert_eq!(health.outcomes.len(), 10);
+    health.record(node, true, now, window);
+    assert_eq!(health.outcomes.len(), 1);
+    health.record(node, false, now, window);
+    assert!(!health.evaluate(window, 0.5, 3, now));
+    assert_eq!(health.outcomes.len(), 2);
+
+    health.record(node, false, now, window);
+    assert!(health.evaluate(window, 0.5, 3, now));
+    assert!(health.paused.is_some());
+
+    // Stays paused without tripping again
+    assert!(!health.evaluate(window, 0.5, 3, now));
+    assert!(health.paused.is_some());
+}
+
+#[test]
+fn test_state_transitions() {
+    let node: NodeID = "001122334455".parse().unwrap();
+    let mut state = PersistentState::default();
+    state.update_node(&node, false);
+    state.update_node(&node, false);
+
+    let node_state = &state.node_state[&node];
+    assert_eq!(node_state.state, RolloutState::Served);
+    assert_eq!(node_state.transitions.len(), 1);
+}
+
+#[tokio::test]
+async fn test_save_and_recover() {
+    let dir = std::env::temp_dir().join(format!("gluon-update-manager-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("state.json");
+    let node: NodeID = "001122334455".parse().unwrap();
+
+    let mut state = PersistentState::default();
+    save(&file, &state, 2).await.unwrap();
+    state.update_node(&node, false);
+    save(&file, &state, 2).await.unwrap();
+    assert!(backup_path(&file, 1).exists());
+
+    // The backup is recent, so the next save does not rotate it
+    state.update_node(&"66778899aabb".parse().unwrap(), false);
+    save(&file, &state, 2).await.unwrap();
+    assert!(!backup_path(&file, 2).exists());
+
+    // Simulate a damaged state file, the newest backup does not know about the node yet
+    std::fs::write(&file, "{\"node_sta").unwrap();
+    let recovered = load(&file, 2).await.unwrap();
+    assert!(!recovered.node_state.contains_key(&node));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_dry_run_separate() {
+    let node: NodeID = "001122334455".parse().unwrap();
+    let mut state = PersistentState::default();
+    state.update_node(&node, true);
+
+    assert!(!state.node_state.contains_key(&node));
+    assert_eq!(state.rollout_state(true).0[&node].state, RolloutState::Served);
+}
+
+#[test]
+fn test_merge() {
+    let node: NodeID = "001122334455".parse().unwrap();
+    let uplink: NodeID = "66778899aabb".parse().unwrap();
+    let start = chrono::Utc::now();
+
+    let mut local = PersistentState::default();
+    local.update_node(&node, false);
+    local.node_state.get_mut(&node).unwrap().update_attempts = 2;
+    local.record_uplink(node, uplink, start);
+
+    let mut imported = PersistentState::default();
+    imported.update_node(&node, false);
+    imported.node_state.get_mut(&node).unwrap().update_attempts = 1;
+    let later = start + chrono::Duration::hours(1);
+    imported.node_state.get_mut(&node).unwrap().transition(RolloutState::Assumed, later);
+    for _ in 0..3 {
+        imported.record_uplink(node, uplink, later);
+    }
+
+    local.merge(imported);
+    let node_state = &local.node_state[&node];
+    assert_eq!(node_state.update_attempts, 2);
+    assert_eq!(node_state.state, RolloutState::Assumed);
+    let record = &local.link_history[&node].uplinks[0];
+    assert_eq!(record.first_seen, start);
+    assert_eq!(record.last_seen, later);
+    assert_eq!(record.observations, 3);
+}
+
+#[test]
+fn test_apply_changes() {
+    let served: NodeID = "001122334455".parse().unwrap();
+    let flashing: NodeID = "66778899aabb".parse().unwrap();
+    let pruned: NodeID = "aabbccddeeff".parse().unwrap();
+    let now = chrono::Utc::now();
+
+    let mut current = PersistentState::default();
+    current.update_node(&flashing, false);
+    current.mark_seen(pruned, now);
+    let before = current.clone();
+
+    let mut after = before.clone();
+    after.node_state.get_mut(&flashing).unwrap().transition(RolloutState::Flashing, now);
+    after.node_state.insert(served, NodeState::default());
+    after.prune(&[pruned]);
+
+    // An update check while the graph is built
+    current.update_node(&served, false);
+
+    current.apply_changes(&before, after);
+    assert_eq!(current.node_state[&flashing].state, RolloutState::Flashing);
+    assert_eq!(current.node_state[&served].state, RolloutState::Served);
+    assert!(!current.last_seen.contains_key(&pruned));
+}
+
+#[test]
+fn test_apply_changes_keeps_pause() {
+    let node: NodeID = "001122334455".parse().unwrap();
+    let now = chrono::Utc::now();
+    let window = chrono::Duration::hours(1);
+
+    let mut current = PersistentState::default();
+    let before = current.clone();
+    let mut after = before.clone();
+    after.rollout.record(node, false, now, window);
+    after.rollout.paused = Some(Pause { since: now, reason: "too many failures".to_owned() });
+
+    // The live state changes between taking the copy and applying the changes
+    current.rollout.record(node, true, now - chrono::Duration::minutes(1), window);
+
+    current.apply_changes(&before, after);
+    assert!(current.rollout.paused.is_some());
+    assert_eq!(current.rollout.outcomes.len(), 2);
+}
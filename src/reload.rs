@@ -0,0 +1,201 @@
+use std::sync::Arc;
+use tokio::fs;
+use tokio::signal::unix::{signal, SignalKind};
+use crate::{MainState, SiteState};
+use crate::config::{Config, SiteConfig};
+
+/// How the sites of a new configuration differ from the running ones
+#[derive(Debug)]
+struct Changes {
+    added: Vec<SiteConfig>,
+    changed: Vec<SiteConfig>,
+    removed: Vec<(String, String)>
+}
+
+/// Compares the running sites with the sites of a new configuration. Changes to how the state of a
+/// running site is stored are refused
+fn diff(running: &[SiteConfig], sites: &[SiteConfig]) -> Result<Changes, failure::Error> {
+    let mut changes = Changes { added: vec![], changed: vec![], removed: vec![] };
+    for site in sites {
+        match running.iter().find(|r| r.name == site.name && r.branch == site.branch) {
+            Some(running_config) => {
+                if let Some(setting) = running_config.restart_required(site) {
+                    return Err(failure::format_err!(
+                        "Changing {} of site {}/{} requires a restart",
+                        setting,
+                        site.name,
+                        site.branch
+                    ));
+                }
+                if running_config != site {
+                    changes.changed.push(site.clone());
+                }
+            },
+            None => changes.added.push(site.clone())
+        }
+    }
+    changes.removed = running.iter()
+        .filter(|r| !sites.iter().any(|s| s.name == r.name && s.branch == r.branch))
+        .map(|r| (r.name.clone(), r.branch.clone()))
+        .collect();
+    Ok(changes)
+}
+
+/// Starts the given sites again after a failed reload and puts them back in place. Returns the
+/// sites which failed to start and are now stopped
+async fn restore(state: &MainState, sites: Vec<SiteConfig>) -> Vec<String> {
+    let mut failed = vec![];
+    for site in sites {
+        let key = (site.name.clone(), site.branch.clone());
+        match crate::start_site(site, &state.journal, &state.state_tx).await {
+            Ok(site) => {
+                state.graphs.write().unwrap().insert(key, site);
+            },
+            Err(e) => {
+                log::error!("Failed to restore site {}/{}: {}", key.0, key.1, e);
+                failed.push(format!("{}/{}", key.0, key.1));
+            }
+        }
+    }
+    failed
+}
+
+/// Starts the given sites and adds them to `started`, stopping at the first one which fails
+async fn start_all(
+    state: &MainState,
+    sites: &[&SiteConfig],
+    started: &mut Vec<Arc<SiteState>>
+) -> Result<(), failure::Error> {
+    for site in sites {
+        started.push(crate::start_site((*site).clone(), &state.journal, &state.state_tx).await?);
+    }
+    Ok(())
+}
+
+/// Reads the configuration file again and applies it. Changed sites get their new configuration
+/// at once, removed sites are stopped after writing their state and added sites are started.
+///
+/// Nothing is changed if the configuration is invalid or changes how the state of a running site is
+/// stored. Added sites are started before any site is stopped, unless they take over the state file
+/// of a removed site. If one of those fails to start, the removed sites are started again and the
+/// error names those which could not be.
+pub async fn reload(state: &MainState) -> Result<(), failure::Error> {
+    let _reloading = state.reloading.lock().await;
+
+    let config = Config::parse(&fs::read_to_string(&state.config_file).await?)?;
+    if config.listen != state.listen_addr {
+        log::warn!("Changing the listen address requires a restart, still listening on {}", state.listen_addr);
+    }
+
+    let running: Vec<_> = state.sites().iter().map(|site| (*site.config()).clone()).collect();
+    let changes = diff(&running, &config.sites)?;
+
+    // The state file of a running site is locked until the site is retired
+    let (takeovers, independent): (Vec<_>, Vec<_>) = changes.added.iter()
+        .partition(|site| running.iter().any(|r| r.state_file == site.state_file));
+
+    let mut started = vec![];
+    if let Err(e) = start_all(state, &independent, &mut started).await {
+        for site in &started {
+            site.retire().await;
+        }
+        return Err(e);
+    }
+
+    let mut retired = vec![];
+    for (name, branch) in &changes.removed {
+        let site = state.graphs.write().unwrap().remove(&(name.clone(), branch.clone()));
+        if let Some(site) = site {
+            log::info!("Removing site {}/{}", name, branch);
+            site.retire().await;
+            retired.push((*site.config()).clone());
+        }
+    }
+
+    if let Err(e) = start_all(state, &takeovers, &mut started).await {
+        for site in &started {
+            site.retire().await;
+        }
+        let failed = restore(state, retired).await;
+        if !failed.is_empty() {
+            return Err(failure::format_err!(
+                "{}. The removed sites {} could not be started again and are stopped",
+                e,
+                failed.join(", ")
+            ));
+        }
+        return Err(e);
+    }
+
+    for site_config in &changes.changed {
+        log::info!("Applying changed configuration of site {}/{}", site_config.name, site_config.branch);
+        if let Some(site) = state.site(&site_config.name, &site_config.branch) {
+            site.set_config(site_config.clone(), &state.journal);
+            if let Err(e) = crate::rebuild_from_cache(&site).await {
+                log::warn!(
+                    "Failed to rebuild graph of site {}/{} from cached mesh data: {}",
+                    site_config.name,
+                    site_config.branch,
+                    e
+                );
+            }
+            site.refresh.notify();
+        }
+    }
+
+    {
+        let mut graphs = state.graphs.write().unwrap();
+        for site in &started {
+            let config = site.config();
+            graphs.insert((config.name.clone(), config.branch.clone()), site.clone());
+        }
+    }
+
+    *state.admin_tokens.write().unwrap() = config.admin_tokens;
+    *state.trusted_proxies.write().unwrap() = config.trusted_proxies;
+
+    log::info!(
+        "Configuration reloaded: {} sites added, {} changed, {} removed",
+        started.len(),
+        changes.changed.len(),
+        changes.removed.len()
+    );
+    let _ = state.state_tx.clone().send(()).await;
+    Ok(())
+}
+
+/// Reloads the configuration whenever the process receives SIGHUP
+pub async fn signal_task(state: Arc<MainState>) -> Result<(), failure::Error> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        log::info!("Received SIGHUP, reloading configuration from {:?}", state.config_file);
+        if let Err(e) = reload(&state).await {
+            log::error!("Configuration not reloaded, keeping the running one: {}", e);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_diff() {
+    use crate::test_util::site_config;
+
+    let running = vec![site_config("2.0")];
+    let unchanged = diff(&running, &running).unwrap();
+    assert!(unchanged.added.is_empty() && unchanged.changed.is_empty() && unchanged.removed.is_empty());
+
+    let changed = diff(&running, &[site_config("3.0")]).unwrap();
+    assert_eq!(changed.changed.len(), 1);
+    assert!(changed.added.is_empty() && changed.removed.is_empty());
+
+    // A renamed site keeping its state file is removed and added again
+    let mut renamed = site_config("2.0");
+    renamed.name = "other".to_owned();
+    let changes = diff(&running, &[renamed]).unwrap();
+    assert_eq!(changes.added.len(), 1);
+    assert_eq!(changes.removed, vec![("site".to_owned(), "stable".to_owned())]);
+
+    let mut moved = site_config("2.0");
+    moved.state_file = "moved.json".into();
+    assert!(diff(&running, &[moved]).is_err());
+}
@@ -0,0 +1,388 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+use futures::future::{BoxFuture, FutureExt};
+use rusqlite::{params, Connection, OptionalExtension};
+use tokio::sync::Mutex;
+use tokio::task;
+use crate::migration;
+use crate::node_id::NodeID;
+use crate::persistence::{PersistentState, RolloutHealth};
+use crate::storage::{Event, Storage};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS node_state (node_id TEXT PRIMARY KEY, data TEXT NOT NULL);
+    CREATE TABLE IF NOT EXISTS dry_run_node_state (node_id TEXT PRIMARY KEY, data TEXT NOT NULL);
+    CREATE TABLE IF NOT EXISTS link_history (node_id TEXT PRIMARY KEY, data TEXT NOT NULL);
+    CREATE TABLE IF NOT EXISTS last_seen (node_id TEXT PRIMARY KEY, data TEXT NOT NULL);
+    CREATE TABLE IF NOT EXISTS overrides (node_id TEXT PRIMARY KEY, data TEXT NOT NULL);
+    CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+    CREATE TABLE IF NOT EXISTS events (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        at TEXT NOT NULL,
+        node_id TEXT,
+        kind TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS events_node_id ON events (node_id, id);
+";
+
+const VERSION: &str = "version";
+const ROLLOUT: &str = "rollout";
+const DRY_RUN_ROLLOUT: &str = "dry_run_rollout";
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+enum Table {
+    NodeState,
+    DryRunNodeState,
+    LinkHistory,
+    LastSeen,
+    Override
+}
+
+impl Table {
+    fn name(self) -> &'static str {
+        match self {
+            Table::NodeState => "node_state",
+            Table::DryRunNodeState => "dry_run_node_state",
+            Table::LinkHistory => "link_history",
+            Table::LastSeen => "last_seen",
+            Table::Override => "overrides"
+        }
+    }
+}
+
+/// Keeps the state in an SQLite database. Only rows which changed since the last save are written,
+/// and every state transition of a node is recorded as an event.
+pub struct SqliteStorage {
+    conn: Arc<std::sync::Mutex<Connection>>,
+    written: Mutex<Written>
+}
+
+/// What is known about the contents of the database, beyond the rows themselves
+#[derive(Default)]
+struct Written {
+    /// How many transitions of each node have been recorded as events
+    transitions: HashMap<NodeID, usize>,
+    meta: HashMap<&'static str, String>
+}
+
+#[derive(Default)]
+struct Changes {
+    upserts: Vec<(Table, NodeID, String)>,
+    deletes: Vec<(Table, NodeID)>,
+    events: Vec<Event>,
+    meta: Vec<(&'static str, String)>
+}
+
+impl Changes {
+    fn is_empty(&self) -> bool {
+        self.upserts.is_empty() && self.deletes.is_empty() && self.events.is_empty() && self.meta.is_empty()
+    }
+}
+
+impl SqliteStorage {
+    pub fn open(file: &Path) -> Result<SqliteStorage, failure::Error> {
+        if !is_database(file)? {
+            return Err(failure::format_err!(
+                "State file {:?} is not an SQLite database. If it holds the state of the json backend, \
+                 export it with state-backend = \"json\" and import it into a new state file with \
+                 state-backend = \"sqlite\"",
+                file
+            ));
+        }
+        let conn = Connection::open(file)?;
+        conn.execute_batch(SCHEMA)?;
+
+        // The version is only written with the first save, opening for an export must not write
+        let mut written = Written::default();
+        if let Some(version) = stored_version(&conn)? {
+            migration::check_version(version.parse()?, file)?;
+            written.meta.insert(VERSION, version);
+        }
+        Ok(SqliteStorage {
+            conn: Arc::new(std::sync::Mutex::new(conn)),
+            written: Mutex::new(written)
+        })
+    }
+}
+
+/// Missing and empty files are turned into a database by opening them
+fn is_database(file: &Path) -> Result<bool, failure::Error> {
+    let mut header = vec![];
+    match std::fs::File::open(file) {
+        Ok(f) => {
+            f.take(16).read_to_end(&mut header)?;
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e.into())
+    }
+    Ok(header.is_empty() || header == b"SQLite format 3\0")
+}
+
+fn stored_version(conn: &Connection) -> Result<Option<String>, failure::Error> {
+    Ok(conn
+        .query_row("SELECT value FROM meta WHERE key = ?1", params![VERSION], |row| row.get(0))
+        .optional()?)
+}
+
+/// The row of the node in every table, `None` where the node has no entry
+fn node_rows(state: &PersistentState, id: &NodeID) -> Result<Vec<(Table, Option<String>)>, failure::Error> {
+    Ok(vec![
+        (Table::NodeState, state.node_state.get(id).map(serde_json::to_string).transpose()?),
+        (Table::DryRunNodeState, state.dry_run.node_state.get(id).map(serde_json::to_string).transpose()?),
+        (Table::LinkHistory, state.link_history.get(id).map(serde_json::to_string).transpose()?),
+        (Table::LastSeen, state.last_seen.get(id).map(serde_json::to_string).transpose()?),
+        (Table::Override, state.overrides.get(id).map(serde_json::to_string).transpose()?)
+    ])
+}
+
+fn read_table(conn: &Connection, table: Table) -> Result<Vec<(NodeID, String)>, failure::Error> {
+    let mut stmt = conn.prepare(&format!("SELECT node_id, data FROM {}", table.name()))?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+    let mut ret = vec![];
+    for row in rows {
+        let (id, data) = row?;
+        let id = id.parse::<NodeID>()
+            .map_err(|_| failure::format_err!("Invalid node id {} in table {}", id, table.name()))?;
+        ret.push((id, data));
+    }
+    Ok(ret)
+}
+
+fn load_blocking(conn: &Connection) -> Result<(PersistentState, Written), failure::Error> {
+    let mut state = PersistentState::default();
+    let mut written = Written::default();
+
+    for (id, data) in read_table(conn, Table::NodeState)? {
+        let node_state: crate::persistence::NodeState = serde_json::from_str(&data)?;
+        written.transitions.insert(id, node_state.transitions.len());
+        state.node_state.insert(id, node_state);
+    }
+    for (id, data) in read_table(conn, Table::DryRunNodeState)? {
+        state.dry_run.node_state.insert(id, serde_json::from_str(&data)?);
+    }
+    for (id, data) in read_table(conn, Table::LinkHistory)? {
+        state.link_history.insert(id, serde_json::from_str(&data)?);
+    }
+    for (id, data) in read_table(conn, Table::LastSeen)? {
+        state.last_seen.insert(id, serde_json::from_str(&data)?);
+    }
+    for (id, data) in read_table(conn, Table::Override)? {
+        state.overrides.insert(id, serde_json::from_str(&data)?);
+    }
+
+    for key in &[ROLLOUT, DRY_RUN_ROLLOUT] {
+        let value: Option<String> = conn
+            .query_row("SELECT value FROM meta WHERE key = ?1", params![key], |row| row.get(0))
+            .optional()?;
+        if let Some(value) = value {
+            *state.rollout_state_mut(*key == DRY_RUN_ROLLOUT).1 = serde_json::from_str(&value)?;
+            written.meta.insert(key, value);
+        }
+    }
+
+    if let Some(version) = stored_version(conn)? {
+        written.meta.insert(VERSION, version);
+    }
+
+    state.backfill_last_seen(chrono::Utc::now());
+    Ok((state, written))
+}
+
+fn write_blocking(conn: &mut Connection, changes: &Changes) -> Result<(), failure::Error> {
+    let tx = conn.transaction()?;
+    for (table, id, data) in &changes.upserts {
+        tx.execute(
+            &format!("INSERT OR REPLACE INTO {} (node_id, data) VALUES (?1, ?2)", table.name()),
+            params![id.to_string(), data]
+        )?;
+    }
+    for (table, id) in &changes.deletes {
+        tx.execute(
+            &format!("DELETE FROM {} WHERE node_id = ?1", table.name()),
+            params![id.to_string()]
+        )?;
+    }
+    for event in &changes.events {
+        tx.execute(
+            "INSERT INTO events (at, node_id, kind) VALUES (?1, ?2, ?3)",
+            params![event.at.to_rfc3339(), event.node_id.map(|id| id.to_string()), event.kind]
+        )?;
+    }
+    for (key, value) in &changes.meta {
+        tx.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)",
+            params![key, value]
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+fn events_blocking(conn: &Connection, node: Option<NodeID>, limit: usize) -> Result<Vec<Event>, failure::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT at, node_id, kind FROM events WHERE ?1 IS NULL OR node_id = ?1 ORDER BY id DESC LIMIT ?2"
+    )?;
+    let rows = stmt.query_map(
+        params![node.map(|id| id.to_string()), limit as i64],
+        |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?, row.get::<_, String>(2)?))
+    )?;
+
+    let mut ret = vec![];
+    for row in rows {
+        let (at, node_id, kind) = row?;
+        ret.push(Event {
+            at: chrono::DateTime::parse_from_rfc3339(&at)?.with_timezone(&chrono::Utc),
+            node_id: node_id.and_then(|id| id.parse().ok()),
+            kind
+        });
+    }
+    Ok(ret)
+}
+
+impl Storage for SqliteStorage {
+    fn load(&self) -> BoxFuture<'_, Result<PersistentState, failure::Error>> {
+        async move {
+            let conn = self.conn.clone();
+            let (state, written) = task::spawn_blocking(move || {
+                load_blocking(&conn.lock().unwrap())
+            }).await??;
+            *self.written.lock().await = written;
+            Ok(state)
+        }.boxed()
+    }
+
+    fn save<'a>(&'a self, state: &'a PersistentState) -> BoxFuture<'a, Result<(), failure::Error>> {
+        async move {
+            let mut written = self.written.lock().await;
+            let now = chrono::Utc::now();
+
+            // Only nodes which changed since the last save are looked at
+            let mut changes = Changes::default();
+            for id in state.dirty() {
+                for (table, data) in node_rows(state, id)? {
+                    match data {
+                        Some(data) => changes.upserts.push((table, *id, data)),
+                        None => changes.deletes.push((table, *id))
+                    }
+                }
+                let known = written.transitions.get(id).copied().unwrap_or(0);
+                let transitions = state.node_state.get(id).map(|s| &s.transitions[..]).unwrap_or_default();
+                for transition in transitions.iter().skip(known) {
+                    changes.events.push(Event {
+                        at: transition.at,
+                        node_id: Some(*id),
+                        kind: serde_json::to_value(transition.state)?
+                            .as_str()
+                            .unwrap_or_default()
+                            .to_owned()
+                    });
+                }
+            }
+
+            let rollout = serde_json::to_string(&state.rollout)?;
+            if written.meta.get(ROLLOUT) != Some(&rollout) {
+                let was_paused = match written.meta.get(ROLLOUT) {
+                    Some(old) => serde_json::from_str::<RolloutHealth>(old)?.paused.is_some(),
+                    None => false
+                };
+                if was_paused != state.rollout.paused.is_some() {
+                    changes.events.push(Event {
+                        at: now,
+                        node_id: None,
+                        kind: if was_paused { "resumed" } else { "paused" }.to_owned()
+                    });
+                }
+                changes.meta.push((ROLLOUT, rollout));
+            }
+
+            let dry_run_rollout = serde_json::to_string(&state.dry_run.rollout)?;
+            if written.meta.get(DRY_RUN_ROLLOUT) != Some(&dry_run_rollout) {
+                changes.meta.push((DRY_RUN_ROLLOUT, dry_run_rollout));
+            }
+
+            if !written.meta.contains_key(VERSION) {
+                changes.meta.push((VERSION, migration::STATE_VERSION.to_string()));
+            }
+
+            if changes.is_empty() {
+                return Ok(());
+            }
+
+            log::debug!(
+                "Writing {} changed rows, {} deleted rows and {} events",
+                changes.upserts.len(),
+                changes.deletes.len(),
+                changes.events.len()
+            );
+            let conn = self.conn.clone();
+            let changes = task::spawn_blocking(move || {
+                write_blocking(&mut conn.lock().unwrap(), &changes).map(|_| changes)
+            }).await??;
+
+            for id in state.dirty() {
+                match state.node_state.get(id) {
+                    Some(node_state) => written.transitions.insert(*id, node_state.transitions.len()),
+                    None => written.transitions.remove(id)
+                };
+            }
+            written.meta.extend(changes.meta);
+            Ok(())
+        }.boxed()
+    }
+
+    fn writes_only_dirty(&self) -> bool {
+        true
+    }
+
+    fn events(&self, node: Option<NodeID>, limit: usize) -> BoxFuture<'_, Result<Vec<Event>, failure::Error>> {
+        let conn = self.conn.clone();
+        async move {
+            task::spawn_blocking(move || events_blocking(&conn.lock().unwrap(), node, limit)).await?
+        }.boxed()
+    }
+}
+
+#[tokio::test]
+async fn test_incremental_save() {
+    let file = std::env::temp_dir().join(format!("gluon-update-manager-test-{}.sqlite", std::process::id()));
+    let node: NodeID = "001122334455".parse().unwrap();
+    let other: NodeID = "66778899aabb".parse().unwrap();
+
+    let storage = SqliteStorage::open(&file).unwrap();
+    assert!(stored_version(&storage.conn.lock().unwrap()).unwrap().is_none());
+    let mut state = PersistentState::default();
+    state.update_node(&node, false);
+    state.update_node(&other, false);
+    state.record_uplink(node, other, chrono::Utc::now());
+    storage.save(&state.take_for_save(true)).await.unwrap();
+
+    state.node_state.remove(&other);
+    state.mark_dirty(other);
+    let snapshot = state.take_for_save(true);
+    assert!(snapshot.node_state.is_empty());
+    storage.save(&snapshot).await.unwrap();
+
+    let reopened = SqliteStorage::open(&file).unwrap();
+    assert!(stored_version(&reopened.conn.lock().unwrap()).unwrap().is_some());
+    let loaded = reopened.load().await.unwrap();
+    assert!(loaded.node_state.contains_key(&node));
+    assert!(!loaded.node_state.contains_key(&other));
+    assert!(loaded.link_history.contains_key(&node));
+
+    let events = reopened.events(Some(node), 10).await.unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].kind, "served");
+
+    std::fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn test_refuse_json_state() {
+    let file = std::env::temp_dir().join(format!("gluon-update-manager-test-{}-json.sqlite", std::process::id()));
+    std::fs::write(&file, "{\"version\": 2}").unwrap();
+    let error = SqliteStorage::open(&file).err().unwrap();
+    assert!(error.to_string().contains("not an SQLite database"));
+    std::fs::remove_file(&file).unwrap();
+}
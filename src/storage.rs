@@ -0,0 +1,107 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use futures::future::{self, BoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+use crate::config::SiteConfig;
+use crate::node_id::NodeID;
+use crate::metrics::Histogram;
+use crate::persistence::{self, PersistentState};
+
+/// Where the persistent state of a site is kept
+pub trait Storage: Send + Sync {
+    fn load(&self) -> BoxFuture<'_, Result<PersistentState, failure::Error>>;
+
+    fn save<'a>(&'a self, state: &'a PersistentState) -> BoxFuture<'a, Result<(), failure::Error>>;
+
+    /// Whether `save` only looks at the nodes marked dirty, so it can be handed a copy of the
+    /// state holding nothing else
+    fn writes_only_dirty(&self) -> bool {
+        false
+    }
+
+    /// Recorded events, newest first, optionally only those concerning a single node
+    fn events(&self, _node: Option<NodeID>, _limit: usize) -> BoxFuture<'_, Result<Vec<Event>, failure::Error>> {
+        future::ready(Err(failure::err_msg("This storage backend does not keep an event history"))).boxed()
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Event {
+    pub at: chrono::DateTime<chrono::Utc>,
+    pub node_id: Option<NodeID>,
+    pub kind: String
+}
+
+/// Counters about state saves of a site
+#[derive(Default)]
+pub struct SaveStats {
+    /// Held for the duration of a save, so an older copy of the state never replaces a newer one
+    pub writing: tokio::sync::Mutex<()>,
+    /// How often a save has been requested, requests arriving close together share one save
+    pub requested: AtomicU64,
+    pub saves: AtomicU64,
+    pub failures: AtomicU64,
+    pub durations: Histogram
+}
+
+#[derive(Serialize, Debug)]
+pub struct SaveStatsSnapshot {
+    pub requested: u64,
+    pub saves: u64,
+    pub failures: u64
+}
+
+impl SaveStats {
+    pub fn record(&self, duration: Duration, success: bool) {
+        self.saves.fetch_add(1, Ordering::Relaxed);
+        if !success {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        self.durations.observe(duration);
+    }
+
+    pub fn snapshot(&self) -> SaveStatsSnapshot {
+        SaveStatsSnapshot {
+            requested: self.requested.load(Ordering::Relaxed),
+            saves: self.saves.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed)
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    #[default]
+    #[serde(rename = "json")]
+    Json,
+    #[serde(rename = "sqlite")]
+    Sqlite
+}
+
+pub fn open(config: &SiteConfig) -> Result<Arc<dyn Storage>, failure::Error> {
+    Ok(match config.state_backend {
+        Backend::Json => Arc::new(JsonStorage {
+            file: config.state_file.clone(),
+            backups: config.state_backups
+        }),
+        Backend::Sqlite => Arc::new(crate::sqlite::SqliteStorage::open(&config.state_file)?)
+    })
+}
+
+/// Keeps the whole state in a single JSON file, which is rewritten on every save
+pub struct JsonStorage {
+    file: PathBuf,
+    backups: usize
+}
+
+impl Storage for JsonStorage {
+    fn load(&self) -> BoxFuture<'_, Result<PersistentState, failure::Error>> {
+        persistence::load(&self.file, self.backups).boxed()
+    }
+
+    fn save<'a>(&'a self, state: &'a PersistentState) -> BoxFuture<'a, Result<(), failure::Error>> {
+        persistence::save(&self.file, state, self.backups).boxed()
+    }
+}
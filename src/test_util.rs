@@ -0,0 +1,111 @@
+//! Fixtures shared by the tests of several modules
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use futures::future::{self, BoxFuture, FutureExt};
+use tokio::sync::{mpsc, Mutex, Notify, RwLock};
+use crate::{MainState, SiteState};
+use crate::config::{AdminToken, SiteConfig};
+use crate::graph::Graph;
+use crate::journal::Journal;
+use crate::meshinfo::MeshInfo;
+use crate::persistence::PersistentState;
+use crate::storage::Storage;
+
+/// A site with the given target version, which sends updates to `/new` and everything else to `/old`
+pub fn site_config(latest_version: &str) -> SiteConfig {
+    toml::from_str(&format!(r#"
+        enabled = true
+        latest-version = "{}"
+        name = "site"
+        branch = "stable"
+        meshinfo = ""
+        on-update = "/new"
+        on-noupdate = "/old"
+        update-default = false
+        node-max-age-days = 14
+        dry-run = false
+        ignore-autoupdate-off = true
+        refresh-interval = 60
+        update-timeout = 3600
+        broken-threshold = 3
+        state-file = "state.json"
+    "#, latest_version)).unwrap()
+}
+
+/// A node as found in the mesh data. The node id and MAC address are derived from `id`
+pub fn node(id: u8, hostname: &str, release: &str, nexthop: Option<u8>) -> serde_json::Value {
+    serde_json::json!({
+        "firstseen": chrono::Utc::now(), "lastseen": chrono::Utc::now(), "uptime": chrono::Utc::now(),
+        "is_online": true, "is_gateway": false, "clients": 0, "clients_wifi24": 0, "clients_other": 0,
+        "rootfs_usage": 0.0, "loadavg": 0.0, "memory_usage": 0.0, "nproc": 1, "model": null,
+        "gateway_nexthop": nexthop.map(|n| format!("0000000000{:02x}", n)), "gateway": null,
+        "node_id": format!("0000000000{:02x}", id), "mac": format!("00:00:00:00:00:{:02x}", id),
+        "addresses": [], "domain": "", "hostname": hostname, "owner": null, "location": null,
+        "firmware": { "base": "", "release": release },
+        "autoupdater": { "enabled": true, "branch": "stable" }
+    })
+}
+
+pub fn meshinfo(nodes: Vec<serde_json::Value>) -> MeshInfo {
+    serde_json::from_value(serde_json::json!({
+        "timestamp": chrono::Utc::now(),
+        "links": [],
+        "nodes": nodes
+    })).unwrap()
+}
+
+/// Keeps nothing, saves succeed without writing anything
+struct NoStorage;
+
+impl Storage for NoStorage {
+    fn load(&self) -> BoxFuture<'_, Result<PersistentState, failure::Error>> {
+        future::ready(Ok(PersistentState::default())).boxed()
+    }
+
+    fn save<'a>(&'a self, _state: &'a PersistentState) -> BoxFuture<'a, Result<(), failure::Error>> {
+        future::ready(Ok(())).boxed()
+    }
+}
+
+/// A site with a graph built from `meshinfo` and its state kept in memory. No background tasks run
+pub fn site_state(config: SiteConfig, meshinfo: &MeshInfo) -> Arc<SiteState> {
+    let journal = Journal::start(None).0;
+    let mut persistent = PersistentState::default();
+    let graph = Graph::build(meshinfo, &config, &mut persistent, &journal);
+    Arc::new(SiteState {
+        graph: RwLock::new(graph),
+        persistent: Arc::new(Mutex::new(persistent)),
+        persistent_saver: mpsc::channel(1).0,
+        storage: Arc::new(NoStorage),
+        save_stats: Default::default(),
+        settings: std::sync::RwLock::new((Arc::new(config), journal)),
+        check_ins: Mutex::new(HashMap::new()),
+        metrics: Default::default(),
+        refresh: Notify::new(),
+        retired: AtomicBool::new(false),
+        stop_saver: Arc::new(Notify::new()),
+        saver: Mutex::new(None)
+    })
+}
+
+/// The state of the service running the given sites. The admin token `secret` belongs to `alice`
+pub fn main_state(sites: Vec<Arc<SiteState>>) -> Arc<MainState> {
+    let graphs = sites.into_iter()
+        .map(|site| {
+            let config = site.config();
+            ((config.name.clone(), config.branch.clone()), site)
+        })
+        .collect();
+    Arc::new(MainState {
+        graphs: std::sync::RwLock::new(graphs),
+        listen_addr: "[::1]:0".parse().unwrap(),
+        admin_tokens: std::sync::RwLock::new(vec![AdminToken { name: "alice".to_owned(), token: "secret".to_owned() }]),
+        trusted_proxies: std::sync::RwLock::new(vec![]),
+        config_file: Default::default(),
+        journal: Journal::start(None).0,
+        state_tx: mpsc::channel(1).0,
+        reloading: Mutex::new(())
+    })
+}
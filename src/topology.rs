@@ -0,0 +1,176 @@
+use std::fmt::Write;
+use crate::config::SiteConfig;
+use crate::graph::{Graph, NodeKey, UpdatePolicy};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    Dot,
+    GraphMl
+}
+
+impl std::str::FromStr for Format {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dot" => Ok(Format::Dot),
+            "graphml" => Ok(Format::GraphMl),
+            _ => Err(failure::format_err!("Unknown graph format {}, expected dot or graphml", s))
+        }
+    }
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Dot => "text/vnd.graphviz; charset=utf-8",
+            Format::GraphMl => "application/graphml+xml; charset=utf-8"
+        }
+    }
+}
+
+fn policy_name(policy: Option<UpdatePolicy>) -> &'static str {
+    policy.map(UpdatePolicy::name).unwrap_or("unknown")
+}
+
+fn policy_color(policy: Option<UpdatePolicy>) -> &'static str {
+    match policy {
+        Some(UpdatePolicy::Pending) => "#ffee99",
+        Some(UpdatePolicy::Ready) => "#99ccff",
+        Some(UpdatePolicy::Finished) => "#99ee99",
+        Some(UpdatePolicy::Broken) => "#ff9999",
+        None => "#dddddd"
+    }
+}
+
+/// Nodes sorted by node id, so the output is stable between refreshes
+fn sorted_nodes(graph: &Graph) -> Vec<NodeKey> {
+    let mut keys: Vec<_> = graph.nodes.keys().collect();
+    keys.sort_by_key(|key| graph.nodes[*key].node.node_id.to_string());
+    keys
+}
+
+pub fn render(graph: &Graph, config: &SiteConfig, format: Format) -> String {
+    match format {
+        Format::Dot => dot(graph, config),
+        Format::GraphMl => graphml(graph, config)
+    }
+}
+
+fn dot_escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Renders the graph for Graphviz, with edges pointing from each node to its uplink
+fn dot(graph: &Graph, config: &SiteConfig) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "digraph \"{}/{}\" {{", dot_escape(&config.name), dot_escape(&config.branch));
+    out.push_str("    rankdir=BT;\n    node [shape=box, style=filled];\n");
+
+    let keys = sorted_nodes(graph);
+    for key in &keys {
+        let node = &graph.nodes[*key].node;
+        let policy = graph.update_policy.get(*key).copied();
+        let _ = writeln!(
+            out,
+            "    \"{}\" [label=\"{}\\n{}\", fillcolor=\"{}\"{}];",
+            node.node_id,
+            dot_escape(&node.hostname),
+            dot_escape(&node.firmware.release),
+            policy_color(policy),
+            if node.is_online { "" } else { ", fontcolor=\"#777777\"" }
+        );
+    }
+    for key in &keys {
+        let container = &graph.nodes[*key];
+        if let Some(uplink) = container.uplink.and_then(|uplink| graph.nodes.get(uplink)) {
+            let _ = writeln!(out, "    \"{}\" -> \"{}\";", container.node.node_id, uplink.node.node_id);
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Renders the graph as GraphML, with edges pointing from each node to its uplink
+fn graphml(graph: &Graph, config: &SiteConfig) -> String {
+    let mut out = String::new();
+    out.push_str(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n",
+        "  <key id=\"hostname\" for=\"node\" attr.name=\"hostname\" attr.type=\"string\"/>\n",
+        "  <key id=\"version\" for=\"node\" attr.name=\"version\" attr.type=\"string\"/>\n",
+        "  <key id=\"policy\" for=\"node\" attr.name=\"policy\" attr.type=\"string\"/>\n",
+        "  <key id=\"color\" for=\"node\" attr.name=\"color\" attr.type=\"string\"/>\n",
+        "  <key id=\"depth\" for=\"node\" attr.name=\"depth\" attr.type=\"int\"/>\n",
+        "  <key id=\"online\" for=\"node\" attr.name=\"online\" attr.type=\"boolean\"/>\n"
+    ));
+    let _ = writeln!(
+        out,
+        "  <graph id=\"{}/{}\" edgedefault=\"directed\">",
+        xml_escape(&config.name),
+        xml_escape(&config.branch)
+    );
+
+    let keys = sorted_nodes(graph);
+    for key in &keys {
+        let node = &graph.nodes[*key].node;
+        let policy = graph.update_policy.get(*key).copied();
+        let _ = writeln!(out, "    <node id=\"{}\">", node.node_id);
+        let _ = writeln!(out, "      <data key=\"hostname\">{}</data>", xml_escape(&node.hostname));
+        let _ = writeln!(out, "      <data key=\"version\">{}</data>", xml_escape(&node.firmware.release));
+        let _ = writeln!(out, "      <data key=\"policy\">{}</data>", policy_name(policy));
+        let _ = writeln!(out, "      <data key=\"color\">{}</data>", policy_color(policy));
+        if let Some(depth) = graph.depths.get(*key) {
+            let _ = writeln!(out, "      <data key=\"depth\">{}</data>", depth);
+        }
+        let _ = writeln!(out, "      <data key=\"online\">{}</data>", node.is_online);
+        out.push_str("    </node>\n");
+    }
+    for key in &keys {
+        let container = &graph.nodes[*key];
+        if let Some(uplink) = container.uplink.and_then(|uplink| graph.nodes.get(uplink)) {
+            let _ = writeln!(
+                out,
+                "    <edge source=\"{}\" target=\"{}\"/>",
+                container.node.node_id,
+                uplink.node.node_id
+            );
+        }
+    }
+    out.push_str("  </graph>\n</graphml>\n");
+    out
+}
+
+#[test]
+fn test_render() {
+    use crate::journal::Journal;
+    use crate::persistence::PersistentState;
+    use crate::test_util::{meshinfo, node, site_config};
+
+    let config = site_config("2.0");
+    let graph = Graph::build(
+        &meshinfo(vec![node(1, "gateway", "2.0", None), node(2, "say \"hi\" & <bye>", "1.0", Some(1))]),
+        &config,
+        &mut PersistentState::default(),
+        &Journal::start(None).0
+    );
+
+    let dot = render(&graph, &config, Format::Dot);
+    assert!(dot.starts_with("digraph \"site/stable\" {"));
+    assert!(dot.contains("\"000000000002\" [label=\"say \\\"hi\\\" & <bye>\\n1.0\""));
+    assert!(dot.contains("\"000000000002\" -> \"000000000001\";"));
+
+    let graphml = render(&graph, &config, Format::GraphMl);
+    assert!(graphml.contains("<data key=\"hostname\">say &quot;hi&quot; &amp; &lt;bye&gt;</data>"));
+    assert!(graphml.contains("<edge source=\"000000000002\" target=\"000000000001\"/>"));
+    assert!(graphml.contains("<data key=\"policy\">finished</data>"));
+}
@@ -0,0 +1,81 @@
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::fs;
+use crate::config::SiteConfig;
+use crate::{migration, storage};
+use crate::lock::StateLock;
+
+const FORMAT: &str = "gluon-update-manager-export";
+
+/// A site state in a form which does not depend on the storage backend
+#[derive(Serialize, Deserialize)]
+struct Export {
+    format: String,
+    site: String,
+    branch: String,
+    exported_at: chrono::DateTime<chrono::Utc>,
+    /// The state in the layout of the exporting version, migrated on import
+    state: Value
+}
+
+pub async fn export(site: &SiteConfig, output: Option<&Path>) -> Result<(), failure::Error> {
+    let state = storage::open(site)?.load().await?;
+    let export = Export {
+        format: FORMAT.to_owned(),
+        site: site.name.clone(),
+        branch: site.branch.clone(),
+        exported_at: chrono::Utc::now(),
+        state: serde_json::to_value(&state)?
+    };
+    let data = serde_json::to_string_pretty(&export)?;
+
+    if let Some(output) = output {
+        fs::write(output, data).await?;
+        log::info!(
+            "Exported state of site {}/{} ({} nodes, {} link histories) to {:?}",
+            site.name,
+            site.branch,
+            state.node_state.len(),
+            state.link_history.len(),
+            output
+        );
+    } else {
+        println!("{}", data);
+    }
+    Ok(())
+}
+
+/// Merges an export into the state of a site, see `PersistentState::merge`
+pub async fn import(site: &SiteConfig, input: &Path) -> Result<(), failure::Error> {
+    let export: Export = serde_json::from_str(&fs::read_to_string(input).await?)?;
+    if export.format != FORMAT {
+        return Err(failure::format_err!("{:?} is not a gluon-update-manager export", input));
+    }
+    if export.site != site.name || export.branch != site.branch {
+        log::warn!(
+            "Importing state exported from site {}/{} into site {}/{}",
+            export.site,
+            export.branch,
+            site.name,
+            site.branch
+        );
+    }
+
+    migration::ensure_supported(&export.state, input)?;
+    let mut imported = migration::migrate(export.state)?;
+    imported.backfill_last_seen(chrono::Utc::now());
+    log::info!(
+        "Importing {} node states and {} link histories exported at {}",
+        imported.node_state.len(),
+        imported.link_history.len(),
+        export.exported_at
+    );
+
+    let _lock = StateLock::acquire(&site.state_file)?;
+    let storage = storage::open(site)?;
+    let mut state = storage.load().await?;
+    state.merge(imported);
+    storage.save(&state).await?;
+    Ok(())
+}
@@ -1,73 +1,124 @@
-use actix_web::{web, App, HttpServer, Responder, HttpRequest, middleware, FromRequest, ResponseError, HttpResponse};
+use actix_web::{web, App, HttpServer, Responder, HttpRequest, HttpResponse};
+use actix_files::NamedFile;
 use crate::MainState;
 use std::sync::Arc;
-use actix_web::dev::{PayloadStream, Payload};
-use futures::future;
-use std::fmt;
-use std::net::IpAddr;
 use crate::graph::UpdatePolicy;
+use crate::journal::Decision;
+use crate::client_addr::ClientAddr;
+use crate::metrics::CheckOutcome;
+use crate::node_id::NodeID;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::Path;
+use serde::Deserialize;
+
+/// Sends a manifest or image from the directory. Range requests are answered, so interrupted
+/// image downloads can be resumed
+fn serve_file(req: &HttpRequest, dir: &Path, file: &str) -> Result<HttpResponse, actix_web::Error> {
+    if Path::new(file).file_name() != Some(OsStr::new(file)) || file.starts_with('.') {
+        return Err(actix_web::error::ErrorNotFound("404 Not Found"));
+    }
+    let named_file = NamedFile::open(dir.join(file))
+        .map_err(|_| actix_web::error::ErrorNotFound("404 Not Found"))?;
+    let named_file = if file.ends_with(".manifest") {
+        named_file.set_content_type(actix_files::file_extension_to_mime("txt"))
+    } else {
+        named_file
+    };
+    named_file.into_response(req)
+}
 
 async fn update_check(
+    req: HttpRequest,
     state: web::Data<Arc<MainState>>,
     web::Path((site, branch, file)): web::Path<(String, String, String)>,
-    ForwardedFor(ip): ForwardedFor
+    ClientAddr(ip): ClientAddr
 ) -> impl Responder {
 
-    let site_state = state.graphs.get(&(site.clone(), "any".to_owned())).
-        or_else(|| state.graphs.get(&(site.clone(), branch)));
+    let site_state = state.site(&site, "any")
+        .or_else(|| state.site(&site, &branch));
 
     if let Some(site_state) = site_state {
+        let config = site_state.config();
         let locked_graph = site_state.graph.read().await;
 
-        let should_update = if site_state.config.enabled {
-            if let Some(node_key) = locked_graph.ip_addrs.get(&ip) {
-                let node = locked_graph.nodes.get(*node_key).unwrap();
-                let pol = locked_graph.update_policy.get(*node_key).unwrap();
+        let node = locked_graph.ip_addrs.get(&ip)
+            .map(|key| (*key, locked_graph.nodes.get(*key).unwrap()));
+
+        if let Some((_, node)) = node {
+            site_state.check_ins.lock().await.insert(node.node.node_id, chrono::Utc::now());
+        }
+
+        // The stored state is authoritative, the graph may have been built before a pause or resume
+        let paused = site_state.persistent.lock().await.rollout_state(config.dry_run).1.paused.is_some();
+        let (should_update, reason) = if paused {
+            log::info!("Rollout for site {} is paused, not performing any action", site);
+            (false, "rollout paused".to_owned())
+        } else if config.enabled {
+            if let Some((node_key, node)) = node {
+                let pol = locked_graph.update_policy.get(node_key).unwrap();
                 match pol {
                     UpdatePolicy::Ready => {
                         log::info!(
                         "Host {} is not updated, pushing update and marking it as updated",
                         node.node.hostname
                     );
-                        let mut p = site_state.persistent.lock().await;
-                        p.update_node(&node.node.node_id);
-                        site_state.persistent_saver.clone().send(()).await.unwrap();
-                        true
+                        site_state.persistent.lock().await.update_node(&node.node.node_id, config.dry_run);
+                        site_state.request_save();
+                        (true, "ready for update".to_owned())
                     },
                     UpdatePolicy::Finished => {
                         log::info!("Host {} is already latest version", node.node.hostname);
-                        true
+                        (true, "already updated".to_owned())
                     }
                     UpdatePolicy::Pending => {
                         log::info!("Host {} is not yet ready to update", node.node.hostname);
-                        false
+                        (false, "waiting for downlinks to update".to_owned())
                     }
                     UpdatePolicy::Broken => {
                         log::info!("Host {} is marked as broken, trying to update anyways...", node.node.hostname);
-                        true
+                        (true, "marked as broken, trying anyways".to_owned())
                     }
                 }
             } else {
-                site_state.config.update_default
+                (config.update_default, format!("unknown node, update-default is {}", config.update_default))
             }
         } else {
             log::info!("Site {} disabled, not performing any action", site);
-            false
+            (false, "site disabled".to_owned())
         };
 
-        Ok(
-            if should_update && !site_state.config.dry_run {
-                let path = format!("{}/{}", site_state.config.on_update, file);
-                HttpResponse::TemporaryRedirect()
-                    .header("Location", path)
-                    .finish()
-            } else {
-                let path = format!("{}/{}", site_state.config.on_noupdate, file);
-                HttpResponse::TemporaryRedirect()
-                    .header("Location", path)
-                    .finish()
-            }
-        )
+        let serve_update = should_update && !config.dry_run;
+        site_state.metrics.record_check(match (should_update, serve_update) {
+            (_, true) => CheckOutcome::Update,
+            (true, false) => CheckOutcome::DryRunUpdate,
+            (false, false) => CheckOutcome::NoUpdate
+        });
+        site_state.journal().record(
+            if serve_update { Decision::Update } else { Decision::NoUpdate },
+            node.map(|(_, n)| &n.node),
+            Some(ip),
+            reason
+        );
+
+        match (&config.update_dir, &config.noupdate_dir) {
+            (Some(update_dir), Some(noupdate_dir)) => {
+                serve_file(&req, if serve_update { update_dir } else { noupdate_dir }, &file)
+            },
+            _ => Ok(
+                if serve_update {
+                    let path = format!("{}/{}", config.on_update, file);
+                    HttpResponse::TemporaryRedirect()
+                        .header("Location", path)
+                        .finish()
+                } else {
+                    let path = format!("{}/{}", config.on_noupdate, file);
+                    HttpResponse::TemporaryRedirect()
+                        .header("Location", path)
+                        .finish()
+                }
+            )
+        }
     } else {
         Err(actix_web::error::ErrorNotFound("404 Not Found"))
     }
@@ -76,12 +127,119 @@ async fn update_check(
 async fn node_dump(
     state: web::Data<Arc<MainState>>
 ) -> impl Responder {
-    let dump = crate::dump::generate(&*state).await;
+    let dump = crate::dump::generate(&state).await;
     web::Json(dump)
 }
 
+async fn metrics(
+    state: web::Data<Arc<MainState>>
+) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(crate::metrics::render(&state).await)
+}
+
+async fn dashboard_index(
+    state: web::Data<Arc<MainState>>
+) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(crate::dashboard::render_index(&state))
+}
+
+async fn dashboard(
+    state: web::Data<Arc<MainState>>,
+    web::Path((site, branch)): web::Path<(String, String)>
+) -> impl Responder {
+    let site_state = state.site(&site, &branch)
+        .ok_or_else(|| actix_web::error::ErrorNotFound("404 Not Found"))?;
+    Ok::<_, actix_web::Error>(
+        HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(crate::dashboard::render_site(&site_state).await)
+    )
+}
+
+async fn topology(
+    state: web::Data<Arc<MainState>>,
+    web::Path((site, branch, format)): web::Path<(String, String, String)>
+) -> impl Responder {
+    let site_state = state.site(&site, &branch)
+        .ok_or_else(|| actix_web::error::ErrorNotFound("404 Not Found"))?;
+    let format = format.parse::<crate::topology::Format>()
+        .map_err(|_| actix_web::error::ErrorNotFound("404 Not Found"))?;
+    let graph = site_state.graph.read().await;
+    Ok::<_, actix_web::Error>(
+        HttpResponse::Ok()
+            .content_type(format.content_type())
+            .body(crate::topology::render(&graph, &site_state.config(), format))
+    )
+}
+
+async fn geojson(
+    state: web::Data<Arc<MainState>>,
+    web::Path((site, branch)): web::Path<(String, String)>
+) -> impl Responder {
+    let site_state = state.site(&site, &branch)
+        .ok_or_else(|| actix_web::error::ErrorNotFound("404 Not Found"))?;
+    Ok::<_, actix_web::Error>(
+        HttpResponse::Ok()
+            .content_type("application/geo+json")
+            .json(crate::geojson::render(&site_state).await)
+    )
+}
+
+async fn link_history(
+    state: web::Data<Arc<MainState>>,
+    web::Path(node_id): web::Path<String>
+) -> impl Responder {
+    let node_id = node_id.parse::<NodeID>()
+        .map_err(|_| actix_web::error::ErrorBadRequest("Invalid node id"))?;
+
+    let mut ret = HashMap::new();
+    for site in state.sites() {
+        if let Some(history) = site.persistent.lock().await.link_history.get(&node_id) {
+            let config = site.config();
+            ret.insert(format!("{}_{}", config.name, config.branch), history.clone());
+        }
+    }
+
+    if ret.is_empty() {
+        Err(actix_web::error::ErrorNotFound("404 Not Found"))
+    } else {
+        Ok(web::Json(ret))
+    }
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    node: Option<String>,
+    limit: Option<usize>
+}
+
+async fn event_history(
+    state: web::Data<Arc<MainState>>,
+    web::Path((site, branch)): web::Path<(String, String)>,
+    query: web::Query<HistoryQuery>
+) -> impl Responder {
+    let site_state = state.site(&site, &branch)
+        .ok_or_else(|| actix_web::error::ErrorNotFound("404 Not Found"))?;
+    let node = match &query.node {
+        Some(node) => Some(
+            node.parse::<NodeID>()
+                .map_err(|_| actix_web::error::ErrorBadRequest("Invalid node id"))?
+        ),
+        None => None
+    };
+
+    let events = site_state.storage.events(node, query.limit.unwrap_or(100))
+        .await
+        .map_err(|e| actix_web::error::ErrorNotImplemented(e.to_string()))?;
+    Ok::<_, actix_web::Error>(web::Json(events))
+}
+
 pub async fn main(state: Arc<MainState>) -> Result<(), failure::Error> {
-    let listen = state.listen_addr.clone();
+    let listen = state.listen_addr;
     HttpServer::new(move || {
         App::new()
             .data(state.clone())
@@ -93,54 +251,73 @@ pub async fn main(state: Arc<MainState>) -> Result<(), failure::Error> {
                 web::resource("/node_dump.json")
                     .route(web::get().to(node_dump))
             )
+            .service(
+                web::resource("/metrics")
+                    .route(web::get().to(metrics))
+            )
+            .service(
+                web::resource("/dashboard")
+                    .route(web::get().to(dashboard_index))
+            )
+            .service(
+                web::resource("/dashboard/{site}/{branch}")
+                    .route(web::get().to(dashboard))
+            )
+            .service(
+                web::resource("/graph/{site}/{branch}.{format}")
+                    .route(web::get().to(topology))
+            )
+            .service(
+                web::resource("/map/{site}/{branch}.geojson")
+                    .route(web::get().to(geojson))
+            )
+            .service(
+                web::resource("/link_history/{node_id}.json")
+                    .route(web::get().to(link_history))
+            )
+            .service(
+                web::resource("/history/{site}/{branch}.json")
+                    .route(web::get().to(event_history))
+            )
+            .service(
+                web::scope("/api/v1")
+                    .configure(crate::api::configure)
+                    .configure(crate::admin::configure)
+            )
     })
-        .bind(&listen)?
+        .bind(listen)?
         .run()
         .await?;
     Ok(())
 }
 
-struct ForwardedFor(IpAddr);
-
-impl FromRequest for ForwardedFor {
-    type Error = actix_web::Error;
-    type Future = future::Ready<Result<Self, Self::Error>>;
-    type Config = ();
-
-    fn from_request(req: &HttpRequest, _payload: &mut Payload<PayloadStream>) -> Self::Future {
-        future::ready((|| {
-            if let Some(hdr) = req.headers().get("X-Forwarded-For") {
-                Ok(ForwardedFor(
-                    hdr.to_str()
-                        .map_err(|_| StringError::new("Header is invalid value"))?
-                        .parse::<IpAddr>()
-                        .map_err(|_| StringError::new("Header is invalid value"))?
-                        .to_owned()
-                ))
-            } else {
-                Err(StringError::new("X-Forwarded-For not set"))?
-            }
-        })())
-    }
-}
+#[test]
+fn test_serve_file() {
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::TestRequest;
 
-#[derive(Debug)]
-struct StringError {
-    message: String
-}
+    let base = std::env::temp_dir().join(format!("gluon-update-manager-test-{}-serve", std::process::id()));
+    let dir = base.join("images");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(base.join("outside.bin"), "secret").unwrap();
+    std::fs::write(dir.join("stable.manifest"), "BRANCH=stable\n").unwrap();
+    std::fs::write(dir.join("image.bin"), vec![0u8; 100]).unwrap();
+    std::fs::write(dir.join(".hidden"), "secret").unwrap();
 
-impl StringError {
-    fn new(msg: &str) -> StringError {
-        StringError {
-            message: msg.to_owned()
-        }
+    let req = TestRequest::default().to_http_request();
+    for file in &["../outside.bin", ".hidden", "missing.bin"] {
+        let error = serve_file(&req, &dir, file).err().unwrap();
+        assert_eq!(error.as_response_error().status_code(), StatusCode::NOT_FOUND);
     }
-}
 
-impl fmt::Display for StringError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Display::fmt(&self.message, f)
-    }
-}
+    let manifest = serve_file(&req, &dir, "stable.manifest").unwrap();
+    assert_eq!(manifest.status(), StatusCode::OK);
+    assert!(manifest.headers().get(header::CONTENT_TYPE).unwrap().to_str().unwrap().starts_with("text/plain"));
 
-impl ResponseError for StringError {}
\ No newline at end of file
+    let req = TestRequest::default().header(header::RANGE, "bytes=10-19").to_http_request();
+    let part = serve_file(&req, &dir, "image.bin").unwrap();
+    assert_eq!(part.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(part.headers().get(header::CONTENT_RANGE).unwrap(), "bytes 10-19/100");
+
+    std::fs::remove_dir_all(&base).unwrap();
+}